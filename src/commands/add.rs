@@ -1,64 +1,65 @@
 use std::error;
 use std::fs;
 use std::path;
-use std::io::Read;
-
-use itertools::Itertools;
 
 use fat;
 
+use super::open_image_for_writing;
+use super::take_option;
+use super::take_partition;
+
 pub fn add_file(args: &[String])
     -> Result<(), Box<error::Error>>
 {
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    let time = match take_option(&mut args, "--time")? {
+        Some(time) => Some(time.parse::<fat::DateTime>()?),
+        None => None,
+    };
     expect_args!(args, 2);
 
     let file_name  = args[0].clone();
     let image_name = args[1].clone();
+    let base_name = match path::Path::new(&file_name).file_name() {
+        Some(n) => n.to_string_lossy().into_owned(),
+        None => file_name.clone(),
+    };
+
+    let mut image = open_image_for_writing(&image_name, partition)?;
+
+    // Adding to a directory keeps the file's own name.
     let fat_file_name = if args.len() > 2 {
-        args[2].clone()
-    } else {
-        match path::Path::new(&file_name).file_name() {
-            Some(n) => n.to_string_lossy().into_owned(),
-            None => file_name.clone(),
+        match image.lookup_dir(&args[2]) {
+            Ok(_) => format!("{}/{}", args[2], base_name),
+            Err(_) => args[2].clone(),
         }
+    } else {
+        base_name
     };
 
-    let mut image = fat::Image::from_file(image_name.clone())?;
-
     // Don't overwrite a preexisting file.
-    if let Ok(_) = image.get_file_entry(file_name.clone()) {
-        return Err(errorf!("file {} already exists", file_name));
+    if let Ok(_) = image.get_file_entry(fat_file_name.clone()) {
+        return Err(errorf!("file {} already exists", fat_file_name));
     }
 
-    // Ensure input file exists.
-    let file = fs::File::open(file_name)?;
+    let mut file = fs::File::open(&file_name)?;
     let metadata = file.metadata()?;
+    if metadata.len() > u32::max_value() as u64 {
+        return Err(errorf!("{} is too large for FAT", file_name));
+    }
 
-    // Create a root dir entry.
-    let (entry, index) =
-        image.create_file_entry(fat_file_name, metadata.len() as u32)?;
-
-    // Get free FAT entries, fill sectors with file data.
-    for chunk in &file.bytes().chunks(image.sector_size()) {
-        let chunk = chunk
-            .map(|b_res| b_res.unwrap_or(0))
-            .collect::<Vec<_>>();
-
-        // Get free sector.
-        let entry_index: usize;
-        match image.get_free_fat_entry() {
-            Some(i) => entry_index = i,
-            None => {
-                // TODO: Remove entries written so far.
-                panic!("image ran out of space while writing file")
-            },
-        }
+    // Allocate a cluster chain for the data and link it to a root entry.
+    image.write_file_from(fat_file_name.clone(), &mut file, metadata.len() as u32)?;
 
-        // Write chunk.
-        image.write_data_sector(entry_index, &chunk)?;
+    // Keep the host file's timestamps unless they are overridden or pinned
+    // for a reproducible build.
+    let mut dir_entry = image.lookup(&fat_file_name)?;
+    dir_entry.entry.copy_host_metadata(&metadata);
+    if let Some(time) = time.or(image.fixed_time()) {
+        dir_entry.entry.set_times(time);
     }
-
-    image.save_file_entry(entry, index)?;
-    image.save(image_name)?;
+    image.save_file_entry(&dir_entry)?;
+    image.flush()?;
     Ok(())
 }
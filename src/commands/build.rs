@@ -0,0 +1,29 @@
+use std::error;
+use std::fs;
+use std::path::Path;
+
+use super::mkfs::take_format_options;
+
+pub fn build_image(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let options = take_format_options(&mut args)?;
+    expect_args!(args, 2);
+
+    let src_dir = args[0].clone();
+    let image_fn = args[1].clone();
+    if !Path::new(&src_dir).is_dir() {
+        return Err(errorf!("{} is not a directory", src_dir));
+    }
+    let builder = options.builder(None)?;
+
+    // The whole tree is copied before anything is written to the image.
+    let file = fs::File::create(&image_fn)?;
+    let result = builder.build_from_dir(&src_dir, file)
+        .and_then(|mut image| image.flush());
+    if result.is_err() {
+        let _ = fs::remove_file(&image_fn);
+    }
+    result
+}
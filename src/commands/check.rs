@@ -0,0 +1,63 @@
+use std::error;
+
+use fat::Severity;
+
+use super::open_image;
+use super::open_image_for_writing;
+use super::take_flag;
+use super::take_partition;
+
+pub fn check_image(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    let repair = take_flag(&mut args, "--repair");
+    let apply = take_flag(&mut args, "--apply");
+    let free_lost = take_flag(&mut args, "--free-lost");
+    expect_args!(args, 1);
+    if (apply || free_lost) && !repair {
+        return Err(From::from("--apply and --free-lost need --repair"));
+    }
+
+    let mut image = if apply {
+        open_image_for_writing(&args[0], partition)?
+    } else {
+        open_image(&args[0], partition)?
+    };
+    let mut report = image.check()?;
+    for problem in report.problems.iter() {
+        println!("{}: {}", problem.severity(), problem);
+    }
+
+    if repair {
+        let repairs = image.plan_repairs(&report, !free_lost)?;
+        for repair in repairs.iter() {
+            println!("{}: {}", if apply { "repair" } else { "would repair" }, repair);
+        }
+        if apply && !repairs.is_empty() {
+            for repair in repairs.iter() {
+                image.apply_repair(repair)?;
+            }
+            image.flush()?;
+            report = image.check()?;
+            for problem in report.problems.iter() {
+                println!("remaining {}: {}", problem.severity(), problem);
+            }
+        } else if !repairs.is_empty() {
+            println!(" Nothing was changed, use --apply to make these repairs");
+        }
+    }
+
+    let errors = report.count(Severity::Error);
+    let warnings = report.count(Severity::Warning);
+    println!(" {} file(s), {} dir(s), {} of {} clusters used",
+        report.files, report.dirs, report.used_clusters, image.cluster_count());
+    println!(" {} error(s), {} warning(s)", errors, warnings);
+    if errors > 0 {
+        return Err(errorf!("{} has errors", args[0]));
+    } else if report.is_clean() {
+        println!(" {} is clean", args[0]);
+    }
+    Ok(())
+}
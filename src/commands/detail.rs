@@ -2,28 +2,30 @@ use std::error;
 
 use fat;
 
+use super::open_image;
+use super::take_partition;
+
 pub fn detail_file(args: &[String])
     -> Result<(), Box<error::Error>>
 {
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
     expect_args!(args, 2);
 
     let image_fn = args[0].clone();
-    let image = fat::Image::from_file(image_fn)?;
+    let image = open_image(&image_fn, partition)?;
 
     let file_metadata = image.get_file_entry(args[1].clone())?;
     println!("{:#?}", file_metadata);
 
-    // XXX: We ignore the u32 entry clusters for now and cast to u16.
-    // In the future we need to convert everything to u32 for FAT32 support.
-
-    let mut cluster_num = file_metadata.entry_cluster() as u16;
+    let mut cluster_num = file_metadata.entry_cluster();
     const CLUSTER_NUMS_PER_LINE: usize = 8;
     'outer: loop {
         for _ in 0 .. CLUSTER_NUMS_PER_LINE {
             let next_cluster = image.get_fat_entry(cluster_num);
             print!("{:#x}\t", cluster_num);
 
-            if !fat::cluster_num_is_valid(next_cluster) {
+            if !fat::cluster_num_is_valid(image.fat_type(), next_cluster) {
                 println!("\n{:#x}", next_cluster);
                 break 'outer;
             }
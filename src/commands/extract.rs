@@ -0,0 +1,28 @@
+use std::error;
+
+use super::open_image;
+use super::take_partition;
+
+pub fn extract_image(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    expect_args!(args, 2);
+
+    let image_fn = args[0].clone();
+    let dest_dir = args[1].clone();
+    let image = open_image(&image_fn, partition)?;
+    let report = image.extract_tree("", &dest_dir)?;
+
+    for &(ref path, ref err) in report.failures.iter() {
+        eprintln!("could not extract {}: {}", path, err);
+    }
+    println!(" {} file(s), {} dir(s) extracted to {}",
+        report.files, report.dirs, dest_dir);
+    if !report.failures.is_empty() {
+        return Err(errorf!("{} file(s) or dir(s) could not be extracted",
+            report.failures.len()));
+    }
+    Ok(())
+}
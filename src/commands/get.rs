@@ -0,0 +1,55 @@
+use std::error;
+use std::fs;
+use std::io;
+use std::io::Write;
+
+use fat;
+
+use super::open_image;
+use super::take_partition;
+
+pub fn get_file(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    expect_args!(args, 2);
+
+    let image_fn = args[0].clone();
+    let file_name = args[1].clone();
+    let dest_name = if args.len() > 2 {
+        args[2].clone()
+    } else {
+        match fat::split_path(&file_name).last() {
+            Some(name) => name.to_string(),
+            None => return Err(errorf!("bad path: \"{}\"", file_name)),
+        }
+    };
+
+    let image = open_image(&image_fn, partition)?;
+    let entry = image.get_file_entry(file_name)?;
+    let data = image.read_file(&entry)?;
+
+    let mut file = fs::File::create(dest_name)?;
+    file.write_all(&data)?;
+    entry.restore_host_metadata(&file)?;
+    Ok(())
+}
+
+pub fn cat_file(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    expect_args!(args, 2);
+
+    let image_fn = args[0].clone();
+    let image = open_image(&image_fn, partition)?;
+    let entry = image.get_file_entry(args[1].clone())?;
+    let data = image.read_file(&entry)?;
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    handle.write_all(&data)?;
+    Ok(())
+}
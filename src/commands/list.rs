@@ -1,38 +1,230 @@
+use std::collections::HashSet;
 use std::error;
 
 use fat;
+use fat::{Dir,DirEntry,Image};
+
+use super::open_image;
+use super::take_flag;
+use super::take_partition;
+
+/// How `ls` lists entries.
+struct ListOptions {
+    /// List subdirectories too, each after its parent.
+    recursive: bool,
+    /// Show attributes, all timestamps and first cluster of each entry.
+    long: bool,
+    /// Show hidden, system, volume label and deleted entries.
+    all: bool,
+}
+
+/// Counts of what was listed.
+#[derive(Default)]
+struct Totals {
+    files: usize,
+    dirs: usize,
+    bytes: u64,
+}
 
 pub fn list_files(args: &[String])
     -> Result<(), Box<error::Error>>
 {
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    let options = ListOptions {
+        recursive: take_flag(&mut args, "-R"),
+        long: take_flag(&mut args, "-l"),
+        all: take_flag(&mut args, "-a"),
+    };
     expect_args!(args, 1);
 
     let image_fn = args[0].clone();
-    let image = fat::Image::from_file(image_fn)?;
+    let image = open_image(&image_fn, partition)?;
+    let dir_path = if args.len() > 1 { args[1].clone() } else { String::new() };
+    let dir = image.lookup_dir(&dir_path)?;
 
     println!(" Volume {}", image.volume_label()?);
     println!(" Volume has {} bytes per sector\n", image.sector_size());
 
+    let mut totals = Totals::default();
+    let mut visited = HashSet::new();
+    visited.insert(dir);
+    list_dir(&image, dir, &display_path(&dir_path), &options, &mut visited, &mut totals)?;
+    if options.recursive {
+        println!("\n Total files listed:");
+        println!("\t{} File(s)\t\t{} bytes", totals.files, totals.bytes);
+        println!("\t{} Dir(s)", totals.dirs);
+    }
+
+    Ok(())
+}
+
+fn list_dir(image: &Image, dir: Dir, path: &str, options: &ListOptions,
+            visited: &mut HashSet<Dir>, totals: &mut Totals)
+    -> Result<(), Box<error::Error>>
+{
+    if options.recursive {
+        println!(" Directory of {}\n", path);
+    }
+    if options.long {
+        println!("{:6}  {:>10}  {:19}  {:23}  {:10}  {:>8}  {}",
+            "Attr", "Size", "Modified", "Created", "Accessed", "Cluster", "Name");
+    }
+
+    let dir_entries = shown_entries(image, dir, options.all)?;
     let mut file_count = 0;
     let mut size_total = 0;
-    for entry in image.root_entries() {
-        if entry.rest_are_free() {
-            break;
-        } else if entry.is_free() || entry.is_volume_label() {
-            continue;
-        }
-        file_count += 1;
-        size_total += entry.file_size;
+    for dir_entry in dir_entries.iter() {
+        let entry = &dir_entry.entry;
+        let size = if entry.is_subdir() {
+            if !entry.is_deleted() && !is_dot_entry(dir_entry) {
+                totals.dirs += 1;
+            }
+            "<DIR>".to_string()
+        } else {
+            if !entry.is_deleted() && !entry.is_volume_label() {
+                file_count += 1;
+                size_total += entry.file_size as u64;
+            }
+            entry.file_size.to_string()
+        };
 
-        println!(
-            "{}\t{}\t{}\t\t{}",
-            entry.last_write_date,
-            entry.last_write_time,
-            entry.file_size,
-            entry.filename().unwrap_or("????????.???".to_string()),
-        );
+        let modified = entry.modified()
+            .map_or(String::new(), |modified| modified.to_string());
+        if options.long {
+            println!(
+                "{}  {:>10}  {:19}  {:23}  {:10}  {:>8}  {}",
+                entry.attribute_flags(),
+                size,
+                modified,
+                entry.created().map_or(String::new(), |created| created.to_string()),
+                entry.accessed().map_or(String::new(), |accessed| accessed.to_string()),
+                entry.entry_cluster(),
+                display_name(dir_entry),
+            );
+        } else {
+            println!(
+                "{:19}\t{}\t\t{}",
+                modified,
+                size,
+                display_name(dir_entry),
+            );
+        }
     }
     println!("\t{} File(s)\t\t{} bytes", file_count, size_total);
+    totals.files += file_count;
+    totals.bytes += size_total;
+
+    if options.recursive {
+        for dir_entry in dir_entries.iter() {
+            let entry = &dir_entry.entry;
+            if !entry.is_subdir() || entry.is_deleted() || is_dot_entry(dir_entry) {
+                continue;
+            }
+            let subdir_path = if path == "/" {
+                format!("/{}", dir_entry.name)
+            } else {
+                format!("{}/{}", path, dir_entry.name)
+            };
+            let subdir = image.entry_dir(entry);
+            if !visited.insert(subdir) {
+                eprintln!("warning: {} was already listed, the directory tree loops",
+                    subdir_path);
+                continue;
+            }
+            println!();
+            list_dir(image, subdir, &subdir_path, options, visited, totals)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn tree_files(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    let all = take_flag(&mut args, "-a");
+    expect_args!(args, 1);
+
+    let image_fn = args[0].clone();
+    let image = open_image(&image_fn, partition)?;
+    let dir_path = if args.len() > 1 { args[1].clone() } else { String::new() };
+    let dir = image.lookup_dir(&dir_path)?;
+
+    println!("{}", display_path(&dir_path));
+    let mut totals = Totals::default();
+    let mut visited = HashSet::new();
+    visited.insert(dir);
+    tree_dir(&image, dir, "", all, &mut visited, &mut totals)?;
+    println!("\n{} dir(s), {} file(s), {} bytes", totals.dirs, totals.files, totals.bytes);
+
+    Ok(())
+}
 
+fn tree_dir(image: &Image, dir: Dir, prefix: &str, all: bool,
+            visited: &mut HashSet<Dir>, totals: &mut Totals)
+    -> Result<(), Box<error::Error>>
+{
+    let dir_entries = shown_entries(image, dir, all)?
+        .into_iter()
+        .filter(|dir_entry| {
+            !dir_entry.entry.is_volume_label() && !is_dot_entry(dir_entry)
+        })
+        .collect::<Vec<_>>();
+    for (i, dir_entry) in dir_entries.iter().enumerate() {
+        let last = i + 1 == dir_entries.len();
+        println!("{}{}{}", prefix, if last { "└── " } else { "├── " },
+            display_name(dir_entry));
+
+        let entry = &dir_entry.entry;
+        if entry.is_deleted() {
+            continue;
+        } else if !entry.is_subdir() {
+            totals.files += 1;
+            totals.bytes += entry.file_size as u64;
+            continue;
+        }
+        totals.dirs += 1;
+        let subdir = image.entry_dir(entry);
+        if visited.insert(subdir) {
+            let subdir_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            tree_dir(image, subdir, &subdir_prefix, all, visited, totals)?;
+        }
+    }
     Ok(())
 }
+
+/// The entries of `dir` to show. Hidden, system and volume label entries
+/// are left out unless `all` is set, which adds deleted entries as well.
+fn shown_entries(image: &Image, dir: Dir, all: bool)
+    -> Result<Vec<DirEntry>, Box<error::Error>>
+{
+    if all {
+        return image.read_dir_with_deleted(dir);
+    }
+    Ok(image.read_dir(dir)?
+        .into_iter()
+        .filter(|dir_entry| {
+            let entry = &dir_entry.entry;
+            !entry.is_hidden() && !entry.is_system() && !entry.is_volume_label()
+        })
+        .collect())
+}
+
+fn is_dot_entry(dir_entry: &DirEntry) -> bool {
+    dir_entry.name == "." || dir_entry.name == ".."
+}
+
+fn display_name(dir_entry: &DirEntry) -> String {
+    if dir_entry.entry.is_deleted() {
+        format!("{} (deleted)", dir_entry.name)
+    } else {
+        dir_entry.name.clone()
+    }
+}
+
+/// `path` within the image as shown in listings, from the root.
+fn display_path(path: &str) -> String {
+    format!("/{}", fat::split_path(path).join("/"))
+}
@@ -0,0 +1,25 @@
+use std::error;
+
+use super::open_image_for_writing;
+use super::take_flag;
+use super::take_partition;
+
+pub fn make_dir(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let parents = take_flag(&mut args, "-p");
+    let partition = take_partition(&mut args)?;
+    expect_args!(args, 2);
+
+    let image_fn = args[0].clone();
+    let mut image = open_image_for_writing(&image_fn, partition)?;
+
+    if parents {
+        image.create_dir_all(&args[1])?;
+    } else {
+        image.create_dir(&args[1])?;
+    }
+    image.flush()?;
+    Ok(())
+}
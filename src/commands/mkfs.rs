@@ -0,0 +1,123 @@
+use std::error;
+use std::fs;
+
+use fat;
+
+use super::source_date_epoch;
+use super::take_option;
+use super::take_partition;
+
+/// Parse a size like `1440K`, `64M` or `2G`. Suffixes are powers of 1024
+/// and a bare number is a count of bytes.
+fn parse_size(size: &str) -> Result<u64, Box<error::Error>> {
+    let upper = size.to_uppercase();
+    let (digits, multiplier) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1024),
+        Some('M') => (&upper[..upper.len() - 1], 1024 * 1024),
+        Some('G') => (&upper[..upper.len() - 1], 1024 * 1024 * 1024),
+        _ => (&upper[..], 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => Ok(n * multiplier),
+        Err(_) => Err(errorf!("bad size: \"{}\"", size)),
+    }
+}
+
+/// The formatting options shared by `mkfs` and `build`.
+pub struct FormatOptions {
+    size: Option<String>,
+    floppy: Option<String>,
+    fat_bits: Option<String>,
+    label: Option<String>,
+}
+
+/// Remove `--size`, `--floppy`, `--fat` and `--label` from `args`.
+pub fn take_format_options(args: &mut Vec<String>)
+    -> Result<FormatOptions, Box<error::Error>>
+{
+    Ok(FormatOptions {
+        size: take_option(args, "--size")?,
+        floppy: take_option(args, "--floppy")?,
+        fat_bits: take_option(args, "--fat")?,
+        label: take_option(args, "--label")?,
+    })
+}
+
+impl FormatOptions {
+    /// Builder for the image the options describe. Without `--size` or
+    /// `--floppy` the image is `default_size` bytes, if that is given.
+    /// Timestamps and the volume serial come from `SOURCE_DATE_EPOCH` if it
+    /// is set.
+    pub fn builder(&self, default_size: Option<u64>)
+        -> Result<fat::ImageBuilder, Box<error::Error>>
+    {
+        let size = match (&self.size, &self.floppy) {
+            (&Some(ref size), _) => Some(parse_size(size)?),
+            (&None, &None) => default_size,
+            (&None, &Some(_)) => None,
+        };
+        let mut builder = match (size, &self.floppy) {
+            (Some(size), &None) => fat::ImageBuilder::new(size),
+            (None, &Some(ref name)) => match fat::FloppyGeometry::by_name(name) {
+                Some(geometry) => fat::ImageBuilder::floppy(geometry),
+                None => return Err(errorf!("unknown floppy format: \"{}\"", name)),
+            },
+            (Some(_), &Some(_)) =>
+                return Err(From::from("--size and --floppy can't be used together")),
+            (None, &None) => return Err(From::from("--size or --floppy is required")),
+        };
+        if let Some(ref bits) = self.fat_bits {
+            builder = builder.fat_type(match bits.as_str() {
+                "12" => fat::FatType::Fat12,
+                "16" => fat::FatType::Fat16,
+                "32" => fat::FatType::Fat32,
+                _ => return Err(errorf!("bad FAT type: \"{}\"", bits)),
+            });
+        }
+        if let Some(ref label) = self.label {
+            builder = builder.label(label);
+        }
+        if let Some(time) = source_date_epoch()? {
+            builder = builder.time(time);
+        }
+        Ok(builder)
+    }
+}
+
+pub fn make_fs(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let options = take_format_options(&mut args)?;
+    let partition = take_partition(&mut args)?;
+    expect_args!(args, 1);
+
+    // Formatting a partition fills it unless a size is given.
+    let partition = match partition {
+        Some(number) => {
+            let mut file = fs::OpenOptions::new()
+                .read(true).write(true).open(&args[0])?;
+            let partition = fat::find_partition(&mut file, number)?;
+            Some((file, partition))
+        },
+        None => None,
+    };
+    let default_size = partition.as_ref().map(|&(_, ref partition)| partition.length);
+    let builder = options.builder(default_size)?;
+
+    if let Some((file, partition)) = partition {
+        if builder.size() > partition.length {
+            return Err(errorf!("partition {} holds only {} bytes",
+                partition.number, partition.length));
+        }
+        let mut image = builder.build_on(partition.slice(file))?;
+        return image.flush();
+    }
+
+    let file = fs::File::create(&args[0])?;
+    let result = builder.build_on(file).and_then(|mut image| image.flush());
+    if result.is_err() {
+        let _ = fs::remove_file(&args[0]);
+    }
+    result
+}
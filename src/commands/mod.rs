@@ -1,20 +1,62 @@
+use std::env;
 use std::error;
+use std::fs;
+
+use fat;
 
 mod add;
+mod build;
+mod check;
 mod detail;
+mod extract;
+mod get;
 mod list;
+mod mkdir;
+mod mkfs;
+mod partitions;
+mod rm;
 
 pub use self::add::add_file as add;
+pub use self::build::build_image as build;
+pub use self::check::check_image as check;
 pub use self::detail::detail_file as detail;
+pub use self::extract::extract_image as extract;
+pub use self::get::get_file as get;
+pub use self::get::cat_file as cat;
 pub use self::list::list_files as list;
+pub use self::list::tree_files as tree;
+pub use self::mkdir::make_dir as mkdir;
+pub use self::mkfs::make_fs as mkfs;
+pub use self::partitions::list_partitions as partitions;
+pub use self::rm::remove_file as rm;
 
 type Command = fn (&[String]) -> Result<(), Box<error::Error>>;
 const COMMANDS: &'static [
     (&'static str, &'static str, &'static str, Command)
 ] = &[
-    ("add", "<file> <image>", "Adds file to image.",   add::add_file),
-    ("ls",  "<image>", "List files on image.",         list::list_files),
+    ("add", "<file> <image> [path] [--time <YYYY-MM-DD HH:MM:SS|@seconds>]",
+        "Adds file to image, keeping its timestamps unless --time is given.",
+        add::add_file),
+    ("ls",  "<image> [-R] [-l] [-a] [dir]",
+        "List files on image, -R for subdirectories too, -l for details and \
+        -a for hidden, system and deleted entries.", list::list_files),
+    ("tree", "<image> [-a] [dir]", "Shows the directory tree of image.", list::tree_files),
     ("detail",  "<image> <file>", "Show file details", detail::detail_file),
+    ("get", "<image> <file> [dest]", "Copies file out of image.", get::get_file),
+    ("cat", "<image> <file>", "Writes file contents to stdout.", get::cat_file),
+    ("rm",  "<image> <file>", "Removes file from image.",  rm::remove_file),
+    ("mkdir", "<image> [-p] <path>", "Creates directory in image.", mkdir::make_dir),
+    ("mkfs", "<image> --size <size>|--floppy <format> [--fat 12|16|32] [--label <name>]",
+        "Formats a new image.", mkfs::make_fs),
+    ("build", "<srcdir> <image> --size <size>|--floppy <format> [--fat 12|16|32] [--label <name>]",
+        "Formats a new image holding a copy of a directory.", build::build_image),
+    ("extract", "<image> <destdir>",
+        "Copies every file and directory out of image, listing any it can't.",
+        extract::extract_image),
+    ("partitions", "<disk>", "Lists partitions of a disk image.",
+        partitions::list_partitions),
+    ("check", "<image> [--repair [--apply] [--free-lost]]",
+        "Checks the filesystem for errors, planning or making repairs.", check::check_image),
 ];
 
 pub fn get_command(name: &String) -> Option<Command> {
@@ -32,4 +74,96 @@ pub fn usages() {
     for &(name, usage, description, _) in COMMANDS {
         println!("\t{} {}: {}", name, usage, description);
     }
+    println!("\nCommands that open an image take --partition <n> to use partition n \
+        of a disk image.\nA partitioned disk opened without it uses its EFI system partition.");
+    println!("\nSetting SOURCE_DATE_EPOCH gives every new entry that time and mkfs a volume \
+        serial\nnumber derived from it, so the same commands build identical images.");
+}
+
+/// The time set by `SOURCE_DATE_EPOCH`, for reproducible builds.
+fn source_date_epoch() -> Result<Option<fat::DateTime>, Box<error::Error>> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(seconds) => match seconds.trim().parse::<i64>() {
+            Ok(seconds) => Ok(Some(fat::DateTime::from_unix(seconds))),
+            Err(_) => Err(errorf!("bad SOURCE_DATE_EPOCH: \"{}\"", seconds)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Open the image at `path`, or partition `partition` of it, warning about a
+/// floppy geometry that matches no standard format.
+fn open_image(path: &str, partition: Option<usize>)
+    -> Result<fat::Image, Box<error::Error>>
+{
+    let image = match partition {
+        Some(number) => fat::Image::from_file_partition(path, number)?,
+        None => match fat::Image::from_file(path) {
+            Ok(image) => image,
+            Err(err) => {
+                // Open the EFI system partition of a whole disk image, or
+                // point out its partitions.
+                let mut file = fs::File::open(path)?;
+                match fat::partitions(&mut file) {
+                    Ok(ref partitions) if partitions.iter().any(|p| p.is_esp()) =>
+                        fat::Image::from_file_esp(path)?,
+                    Ok(ref partitions) if !partitions.is_empty() =>
+                        return Err(errorf!("{}: {} is a partitioned disk, \
+                            choose a partition with --partition", err, path)),
+                    _ => return Err(err),
+                }
+            },
+        },
+    };
+    if let Some(warning) = fat::geometry_warning(&image.bios_parameter()) {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(image)
+}
+
+/// Open an image like `open_image` for a command that creates entries,
+/// which get the time from `SOURCE_DATE_EPOCH` if it is set.
+fn open_image_for_writing(path: &str, partition: Option<usize>)
+    -> Result<fat::Image, Box<error::Error>>
+{
+    let time = source_date_epoch()?;
+    let mut image = open_image(path, partition)?;
+    image.set_fixed_time(time);
+    Ok(image)
+}
+
+/// Remove `--partition N` from `args`, returning the partition number.
+fn take_partition(args: &mut Vec<String>)
+    -> Result<Option<usize>, Box<error::Error>>
+{
+    match take_option(args, "--partition")? {
+        Some(number) => match number.parse::<usize>() {
+            Ok(number) if number > 0 => Ok(Some(number)),
+            _ => Err(errorf!("bad partition number: \"{}\"", number)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Remove `flag` from `args`, returning whether it was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+/// Remove `option` and the value following it from `args`, returning the
+/// value if the option was present.
+fn take_option(args: &mut Vec<String>, option: &str)
+    -> Result<Option<String>, Box<error::Error>>
+{
+    match args.iter().position(|arg| arg == option) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        },
+        Some(_) => Err(errorf!("{} needs a value", option)),
+        None => Ok(None),
+    }
 }
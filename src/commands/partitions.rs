@@ -0,0 +1,32 @@
+use std::error;
+use std::fs;
+
+use fat;
+
+pub fn list_partitions(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    expect_args!(args, 1);
+
+    let mut file = fs::File::open(&args[0])?;
+    let partitions = fat::partitions(&mut file)?;
+    if partitions.is_empty() {
+        println!(" Disk has no partitions");
+        return Ok(());
+    }
+
+    println!("{:>3}  {:>12}  {:>12}  {:<20}  {:<5}  {}",
+        "#", "Start", "Size", "Type", "FAT", "Name");
+    for partition in partitions {
+        let fat_type = match partition.fat_type(&mut file) {
+            Some(fat_type) => fat_type.name(),
+            None => "-",
+        };
+        let number = format!("{}{}",
+            if partition.bootable { "*" } else { "" }, partition.number);
+        println!("{:>3}  {:>12}  {:>12}  {:<20}  {:<5}  {}",
+            number, partition.start, partition.length,
+            partition.partition_type.to_string(), fat_type, partition.name);
+    }
+    Ok(())
+}
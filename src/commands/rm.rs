@@ -0,0 +1,19 @@
+use std::error;
+
+use super::open_image;
+use super::take_partition;
+
+pub fn remove_file(args: &[String])
+    -> Result<(), Box<error::Error>>
+{
+    let mut args = args.to_vec();
+    let partition = take_partition(&mut args)?;
+    expect_args!(args, 2);
+
+    let image_fn = args[0].clone();
+    let mut image = open_image(&image_fn, partition)?;
+
+    image.remove_file(args[1].clone())?;
+    image.flush()?;
+    Ok(())
+}
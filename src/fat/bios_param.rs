@@ -2,15 +2,24 @@ extern crate byteorder;
 
 use std::error;
 use std::fs;
-use std::io::{Read,SeekFrom,Seek};
+use std::io;
+use std::io::{Read,Write,SeekFrom,Seek};
 use std::path::Path;
 
 use self::byteorder::{LittleEndian,ByteOrder};
 
+use fat::FatType;
+
+/// Size of the boot sector holding the BPB, regardless of sector size.
+pub const BOOT_SECTOR_SIZE: usize = 512;
+
 #[derive(Clone,Debug)]
 
-/// BIOS parameter block describes the FAT filesystem.
+/// BIOS parameter block describes the FAT filesystem. It models the whole
+/// boot sector so that it can be written back exactly as it was read.
 pub struct BIOSParam {
+    pub jump: [u8; 3],
+    pub oem_name: [u8; 8],
     pub bytes_per_sector: u16,
     pub sectors_per_cluster: u8,
     pub reserved_sectors: u16,
@@ -19,6 +28,35 @@ pub struct BIOSParam {
     pub sectors: u32,
     pub media_id: u8,
     pub sectors_per_fat: u32,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub hidden_sectors: u32,
+
+    // FAT32 extended BPB, zero on FAT12 and FAT16.
+    pub ext_flags: u16,
+    pub fs_version: u16,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+    pub fat32_reserved: [u8; 12],
+
+    // Extended boot record, which follows the FAT32 fields when present.
+    pub drive_number: u8,
+    pub ebr_reserved: u8,
+    pub boot_signature: u8,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+    pub fs_type: [u8; 8],
+
+    /// Boot code between the extended boot record and the signature.
+    pub boot_code: Vec<u8>,
+    pub signature: u16,
+
+    // Whether the BPB uses the FAT32 layout.
+    fat32_layout: bool,
+    // The 16 and 32 bit sector counts as read. They are written back as is
+    // while they still agree with `sectors`.
+    sector_fields: (u16, u32),
 }
 
 #[test]
@@ -29,6 +67,44 @@ fn test_bios_param_calculations() {
     params.sectors_per_cluster = 4;
     assert_eq!(params.len(), 524288 as usize);
     assert_eq!(params.clusters(), 256 as usize);
+    assert_eq!(params.fat_type(), FatType::Fat12);
+}
+
+#[test]
+fn test_bios_param_round_trip() {
+    let mut fat16 = [0u8; BOOT_SECTOR_SIZE];
+    fat16[0..11].copy_from_slice(b"\xeb\x3c\x90MSDOS5.0");
+    fat16[11..36].copy_from_slice(&[
+        0x00, 0x02, 0x04, 0x01, 0x00, 0x02, 0x00, 0x02, 0x00, 0x00, 0xf8,
+        0x40, 0x00, 0x20, 0x00, 0x40, 0x00, 0x3f, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x00]);
+    fat16[36..62].copy_from_slice(b"\x80\x00\x29\x78\x56\x34\x12BOOT       FAT16   ");
+    fat16[62] = 0xfa;
+    fat16[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let bpb = BIOSParam::from_bytes(&fat16).unwrap();
+    assert_eq!(bpb.sectors, 65536);
+    assert_eq!(bpb.volume_id, 0x12345678);
+    assert_eq!(&bpb.to_bytes()[..], &fat16[..]);
+
+    let mut fat32 = [0u8; BOOT_SECTOR_SIZE];
+    fat32[0..11].copy_from_slice(b"\xeb\x58\x90mkfs.fat");
+    fat32[11..52].copy_from_slice(&[
+        0x00, 0x02, 0x08, 0x20, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0xf8,
+        0x00, 0x00, 0x20, 0x00, 0x40, 0x00, 0x00, 0x08, 0x00, 0x00,
+        0x00, 0x00, 0x20, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00]);
+    fat32[64..90].copy_from_slice(b"\x80\x00\x29\xef\xbe\xad\xdeNO NAME    FAT32   ");
+    fat32[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut bpb = BIOSParam::from_bytes(&fat32).unwrap();
+    assert_eq!(bpb.fat_type(), FatType::Fat32);
+    assert_eq!(bpb.sectors_per_fat, 1024);
+    assert_eq!(bpb.hidden_sectors, 2048);
+    assert_eq!(&bpb.to_bytes()[..], &fat32[..]);
+
+    bpb.volume_label.copy_from_slice(b"EFI        ");
+    assert_eq!(&bpb.to_bytes()[71..82], b"EFI        ");
 }
 
 #[allow(dead_code)]
@@ -36,6 +112,8 @@ impl BIOSParam {
     /// Create a new empty BIOS parameter block (BPB)
     pub fn new() -> BIOSParam {
         BIOSParam {
+            jump: [0; 3],
+            oem_name: [' ' as u8; 8],
             bytes_per_sector: 0,
             sectors_per_cluster: 0,
             reserved_sectors: 0,
@@ -44,6 +122,25 @@ impl BIOSParam {
             sectors: 0,
             media_id: 0,
             sectors_per_fat: 0,
+            sectors_per_track: 0,
+            heads: 0,
+            hidden_sectors: 0,
+            ext_flags: 0,
+            fs_version: 0,
+            root_cluster: 0,
+            fs_info_sector: 0,
+            backup_boot_sector: 0,
+            fat32_reserved: [0; 12],
+            drive_number: 0,
+            ebr_reserved: 0,
+            boot_signature: 0,
+            volume_id: 0,
+            volume_label: [' ' as u8; 11],
+            fs_type: [' ' as u8; 8],
+            boot_code: Vec::new(),
+            signature: 0xaa55,
+            fat32_layout: false,
+            sector_fields: (0, 0),
         }
     }
 
@@ -51,42 +148,214 @@ impl BIOSParam {
     pub fn from_file<P: AsRef<Path>>(image_fn: P, offset: usize)
         -> Result<BIOSParam, Box<error::Error>>
     {
-        let mut boot_sector: Vec<u8> = vec![0; 512];
         let mut file = fs::File::open(image_fn.as_ref())?;
         file.seek(SeekFrom::Start(offset as u64))?;
-        file.read_exact(&mut boot_sector)?;
+        BIOSParam::read_from(&mut file)
+    }
+
+    /// Read the BIOS Parameter Block (BPB) from the boot sector at the
+    /// current position of `reader`.
+    pub fn read_from<R: Read>(reader: &mut R)
+        -> Result<BIOSParam, Box<error::Error>>
+    {
+        let mut boot_sector: Vec<u8> = vec![0; BOOT_SECTOR_SIZE];
+        reader.read_exact(&mut boot_sector)?;
+        BIOSParam::from_bytes(&boot_sector)
+    }
+
+    /// Parse the BIOS Parameter Block (BPB) from a boot sector.
+    pub fn from_bytes(boot_sector: &[u8])
+        -> Result<BIOSParam, Box<error::Error>>
+    {
+        if boot_sector.len() < BOOT_SECTOR_SIZE {
+            return Err(From::from(format!(
+                "boot sector is {} bytes, expected {}",
+                boot_sector.len(), BOOT_SECTOR_SIZE)));
+        }
 
         let mut params = BIOSParam::new();
 
+        params.jump.copy_from_slice(&boot_sector[0..3]);
+        params.oem_name.copy_from_slice(&boot_sector[3..11]);
         params.bytes_per_sector = LittleEndian::read_u16(&boot_sector[11..13]);
         params.sectors_per_cluster = boot_sector[13];
         params.reserved_sectors = LittleEndian::read_u16(&boot_sector[14..16]);
         params.fat_count = boot_sector[16];
         params.max_roots = LittleEndian::read_u16(&boot_sector[17..19]);
-        params.sectors = LittleEndian::read_u16(&boot_sector[19..21]) as u32;
+        params.sector_fields = (
+            LittleEndian::read_u16(&boot_sector[19..21]),
+            LittleEndian::read_u32(&boot_sector[32..36]),
+        );
+        params.sectors = params.sector_fields.0 as u32;
         if params.sectors == 0 {
             // 4 byte sector count at 0x020
-            params.sectors = LittleEndian::read_u32(&boot_sector[32..37]);
+            params.sectors = params.sector_fields.1;
         }
         params.media_id = boot_sector[21];
         params.sectors_per_fat = LittleEndian::read_u16(&boot_sector[22..24]) as u32;
-        if params.sectors_per_fat == 0 {
-            // 4 byte sectors per fat count at 0x024
-            params.sectors_per_fat = LittleEndian::read_u32(&boot_sector[36..41]);
-        }
+        params.sectors_per_track = LittleEndian::read_u16(&boot_sector[24..26]);
+        params.heads = LittleEndian::read_u16(&boot_sector[26..28]);
+        params.hidden_sectors = LittleEndian::read_u32(&boot_sector[28..32]);
+
+        let ebr = if params.sectors_per_fat == 0 {
+            // 4 byte sectors per fat count at 0x024, followed by the rest
+            // of the FAT32 extended BPB
+            params.fat32_layout = true;
+            params.sectors_per_fat = LittleEndian::read_u32(&boot_sector[36..40]);
+            params.ext_flags = LittleEndian::read_u16(&boot_sector[40..42]);
+            params.fs_version = LittleEndian::read_u16(&boot_sector[42..44]);
+            params.root_cluster = LittleEndian::read_u32(&boot_sector[44..48]);
+            params.fs_info_sector = LittleEndian::read_u16(&boot_sector[48..50]);
+            params.backup_boot_sector = LittleEndian::read_u16(&boot_sector[50..52]);
+            params.fat32_reserved.copy_from_slice(&boot_sector[52..64]);
+            64
+        } else {
+            36
+        };
+
+        params.drive_number = boot_sector[ebr];
+        params.ebr_reserved = boot_sector[ebr + 1];
+        params.boot_signature = boot_sector[ebr + 2];
+        params.volume_id = LittleEndian::read_u32(&boot_sector[ebr + 3 .. ebr + 7]);
+        params.volume_label.copy_from_slice(&boot_sector[ebr + 7 .. ebr + 18]);
+        params.fs_type.copy_from_slice(&boot_sector[ebr + 18 .. ebr + 26]);
+        params.boot_code = boot_sector[ebr + 26 .. 510].to_vec();
+        params.signature = LittleEndian::read_u16(&boot_sector[510..512]);
         return Ok(params);
     }
 
+    /// Whether the BPB describes a plausible FAT filesystem.
+    pub fn is_valid(&self) -> bool {
+        let bytes_per_sector = self.bytes_per_sector;
+        bytes_per_sector >= 512 && bytes_per_sector <= 4096
+            && bytes_per_sector.is_power_of_two()
+            && self.sectors_per_cluster.is_power_of_two()
+            && self.reserved_sectors > 0
+            && self.fat_count > 0
+            && self.sectors_per_fat > 0
+            && self.data_start_sector() < self.sectors as usize
+    }
+
+    /// Whether the BPB uses the FAT32 layout, with the extended boot record
+    /// after the FAT32 fields.
+    pub fn is_fat32_layout(&self) -> bool {
+        self.fat32_layout || self.fat_type() == FatType::Fat32
+    }
+
+    /// Serialize the BIOS Parameter Block into a boot sector. Parsing a boot
+    /// sector and serializing it again gives back the same bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut boot_sector = vec![0; BOOT_SECTOR_SIZE];
+        let fat32_layout = self.is_fat32_layout();
+
+        boot_sector[0..3].copy_from_slice(&self.jump);
+        boot_sector[3..11].copy_from_slice(&self.oem_name);
+        LittleEndian::write_u16(&mut boot_sector[11..13], self.bytes_per_sector);
+        boot_sector[13] = self.sectors_per_cluster;
+        LittleEndian::write_u16(&mut boot_sector[14..16], self.reserved_sectors);
+        boot_sector[16] = self.fat_count;
+        LittleEndian::write_u16(&mut boot_sector[17..19], self.max_roots);
+
+        let (sectors_16, sectors_32) = self.sector_fields;
+        let unchanged = if sectors_16 != 0 {
+            sectors_16 as u32 == self.sectors
+        } else {
+            sectors_32 == self.sectors
+        };
+        let (sectors_16, sectors_32) = if unchanged {
+            (sectors_16, sectors_32)
+        } else if self.sectors <= u16::max_value() as u32 && !fat32_layout {
+            (self.sectors as u16, 0)
+        } else {
+            (0, self.sectors)
+        };
+        LittleEndian::write_u16(&mut boot_sector[19..21], sectors_16);
+        LittleEndian::write_u32(&mut boot_sector[32..36], sectors_32);
+
+        boot_sector[21] = self.media_id;
+        LittleEndian::write_u16(&mut boot_sector[24..26], self.sectors_per_track);
+        LittleEndian::write_u16(&mut boot_sector[26..28], self.heads);
+        LittleEndian::write_u32(&mut boot_sector[28..32], self.hidden_sectors);
+
+        let ebr = if fat32_layout {
+            LittleEndian::write_u32(&mut boot_sector[36..40], self.sectors_per_fat);
+            LittleEndian::write_u16(&mut boot_sector[40..42], self.ext_flags);
+            LittleEndian::write_u16(&mut boot_sector[42..44], self.fs_version);
+            LittleEndian::write_u32(&mut boot_sector[44..48], self.root_cluster);
+            LittleEndian::write_u16(&mut boot_sector[48..50], self.fs_info_sector);
+            LittleEndian::write_u16(&mut boot_sector[50..52], self.backup_boot_sector);
+            boot_sector[52..64].copy_from_slice(&self.fat32_reserved);
+            64
+        } else {
+            LittleEndian::write_u16(
+                &mut boot_sector[22..24], self.sectors_per_fat as u16);
+            36
+        };
+
+        boot_sector[ebr] = self.drive_number;
+        boot_sector[ebr + 1] = self.ebr_reserved;
+        boot_sector[ebr + 2] = self.boot_signature;
+        LittleEndian::write_u32(&mut boot_sector[ebr + 3 .. ebr + 7], self.volume_id);
+        boot_sector[ebr + 7 .. ebr + 18].copy_from_slice(&self.volume_label);
+        boot_sector[ebr + 18 .. ebr + 26].copy_from_slice(&self.fs_type);
+
+        let code = ebr + 26;
+        let code_len = self.boot_code.len().min(510 - code);
+        boot_sector[code .. code + code_len]
+            .copy_from_slice(&self.boot_code[..code_len]);
+        LittleEndian::write_u16(&mut boot_sector[510..512], self.signature);
+        boot_sector
+    }
+
+    /// Write the serialized boot sector to `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
     /// Reported length of FAT filesystem in bytes.
     pub fn len(&self) -> usize {
         return self.sectors as usize * self.bytes_per_sector as usize;
     }
 
-    /// Reported number of clusters within FAT filesystem.
+    /// Number of sectors occupied by the fixed size root directory.
+    pub fn root_dir_sectors(&self) -> usize {
+        if self.bytes_per_sector == 0 {
+            return 0;
+        }
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        (self.max_roots as usize * 32 + bytes_per_sector - 1) / bytes_per_sector
+    }
+
+    /// Number of sectors before the start of the data area.
+    pub fn data_start_sector(&self) -> usize {
+        self.reserved_sectors as usize
+            + self.fat_count as usize * self.sectors_per_fat as usize
+            + self.root_dir_sectors()
+    }
+
+    /// Reported number of data clusters within FAT filesystem.
     pub fn clusters(&self) -> usize {
         if self.sectors_per_cluster == 0 {
             return 0;
         }
-        return self.sectors as usize / self.sectors_per_cluster as usize;
+        let data_sectors =
+            (self.sectors as usize).saturating_sub(self.data_start_sector());
+        return data_sectors / self.sectors_per_cluster as usize;
+    }
+
+    /// FAT type, as determined by the number of data clusters.
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_cluster_count(self.clusters())
+    }
+
+    /// Whether FAT updates are mirrored to every copy of the FAT. FAT32 can
+    /// disable mirroring with bit 7 of the extended flags.
+    pub fn fat_mirroring(&self) -> bool {
+        self.ext_flags & 0x80 == 0
+    }
+
+    /// Index of the FAT copy that is read from.
+    pub fn active_fat(&self) -> usize {
+        if self.fat_mirroring() { 0 } else { (self.ext_flags & 0x0f) as usize }
     }
 }
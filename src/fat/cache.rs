@@ -0,0 +1,236 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::SeekFrom;
+
+use fat::BlockDevice;
+
+/// Number of sectors kept in memory before the least recently used is evicted.
+pub const CACHE_SECTORS: usize = 2048;
+
+struct CachedSector {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Reads and writes a device one sector at a time, keeping recently used
+/// sectors in memory. Writes stay in memory until `flush` or until the cache
+/// is full, when the least recently used sector is written back if dirty and
+/// dropped.
+pub struct SectorCache {
+    device: Box<BlockDevice>,
+    sector_size: usize,
+    capacity: usize,
+    sectors: BTreeMap<u64, CachedSector>,
+    /// Cached sector numbers by when they were last used, oldest first.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+#[test]
+fn test_sector_cache() {
+    let device = io::Cursor::new(vec![0x11; 8 * 512]);
+    let mut cache = SectorCache::new(Box::new(device), 512, 3);
+
+    let mut buf = [0; 4];
+    cache.read(510, &mut buf).unwrap();
+    assert_eq!(buf, [0x11; 4]);
+
+    cache.write(1020, &[1, 2, 3, 4, 5, 6]).unwrap();
+    cache.write(3 * 512, &[7; 512]).unwrap();
+    assert_eq!(cache.dirty_count(), 3);
+
+    let mut raw = [0; 8];
+    cache.read_raw(1018, &mut raw).unwrap();
+    assert_eq!(raw, [0x11, 0x11, 1, 2, 3, 4, 5, 6]);
+
+    // Reading more sectors writes the least recently used dirty ones back.
+    cache.read(5 * 512, &mut buf).unwrap();
+    cache.read(6 * 512, &mut buf).unwrap();
+    assert_eq!(cache.dirty_count(), 1);
+    assert_eq!(cache.sectors.len(), 3);
+
+    // Using a sector keeps it from being evicted next.
+    cache.read(3 * 512, &mut buf).unwrap();
+    cache.read(7 * 512, &mut buf).unwrap();
+    assert!(cache.sectors.contains_key(&3));
+    assert!(!cache.sectors.contains_key(&5));
+
+    cache.read(1018, &mut raw).unwrap();
+    assert_eq!(raw, [0x11, 0x11, 1, 2, 3, 4, 5, 6]);
+
+    cache.flush().unwrap();
+    assert_eq!(cache.dirty_count(), 0);
+    let mut sector = vec![0; 512];
+    cache.read_raw(3 * 512, &mut sector).unwrap();
+    assert_eq!(sector, vec![7; 512]);
+}
+
+impl SectorCache {
+    pub fn new(device: Box<BlockDevice>, sector_size: usize, capacity: usize)
+        -> SectorCache
+    {
+        SectorCache {
+            device: device,
+            sector_size: sector_size,
+            capacity: cmp::max(capacity, 1),
+            sectors: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Number of sectors waiting to be written to the device.
+    pub fn dirty_count(&self) -> usize {
+        self.sectors.values().filter(|sector| sector.dirty).count()
+    }
+
+    /// Read `buf.len()` bytes starting at byte `offset` of the device.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let (number, start) = self.split(position);
+            let len = cmp::min(self.sector_size - start, buf.len() - done);
+            let sector = self.sector(number, false)?;
+            buf[done .. done + len].copy_from_slice(&sector.data[start .. start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write `data` starting at byte `offset` of the device. The sectors are
+    /// only marked dirty; they reach the device on `flush` or when evicted.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let (number, start) = self.split(position);
+            let len = cmp::min(self.sector_size - start, data.len() - done);
+            // A sector that is overwritten completely needn't be read first.
+            let whole = len == self.sector_size;
+            let sector = self.sector(number, whole)?;
+            sector.data[start .. start + len].copy_from_slice(&data[done .. done + len]);
+            sector.dirty = true;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Read from the device without filling the cache, as the device would
+    /// read after a flush.
+    pub fn read_raw(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.device.seek(SeekFrom::Start(offset))?;
+        self.device.read_exact(buf)?;
+
+        let sector_size = self.sector_size as u64;
+        let end = offset + buf.len() as u64;
+        let first = offset / sector_size;
+        for (&number, sector) in self.sectors.range(first ..) {
+            let sector_start = number * sector_size;
+            if sector_start >= end {
+                break;
+            }
+            if !sector.dirty {
+                continue;
+            }
+            let from = cmp::max(sector_start, offset);
+            let to = cmp::min(sector_start + sector_size, end);
+            buf[(from - offset) as usize .. (to - offset) as usize].copy_from_slice(
+                &sector.data[(from - sector_start) as usize .. (to - sector_start) as usize]);
+        }
+        Ok(())
+    }
+
+    /// Write straight to the device, bypassing the cache. Any cached copy of
+    /// the sectors written is dropped.
+    pub fn write_raw(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let sector_size = self.sector_size as u64;
+        let first = offset / sector_size;
+        let last = (offset + data.len() as u64 + sector_size - 1) / sector_size;
+        for number in first .. last {
+            if let Some(sector) = self.sectors.remove(&number) {
+                self.lru.remove(&sector.last_used);
+            }
+        }
+        self.device.seek(SeekFrom::Start(offset))?;
+        self.device.write_all(data)
+    }
+
+    /// Write every dirty sector to the device, in order.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let sector_size = self.sector_size as u64;
+        for (&number, sector) in self.sectors.iter_mut() {
+            if !sector.dirty {
+                continue;
+            }
+            self.device.seek(SeekFrom::Start(number * sector_size))?;
+            self.device.write_all(&sector.data)?;
+            sector.dirty = false;
+        }
+        self.device.flush()
+    }
+
+    /// Split a byte offset into a sector number and an offset within it.
+    fn split(&self, offset: u64) -> (u64, usize) {
+        let sector_size = self.sector_size as u64;
+        (offset / sector_size, (offset % sector_size) as usize)
+    }
+
+    /// Cached sector `number`, loading it from the device unless `fresh`
+    /// says its contents are about to be overwritten.
+    fn sector(&mut self, number: u64, fresh: bool)
+        -> io::Result<&mut CachedSector>
+    {
+        self.clock += 1;
+        if !self.sectors.contains_key(&number) {
+            self.evict()?;
+            let mut data = vec![0; self.sector_size];
+            if !fresh {
+                self.device.seek(SeekFrom::Start(number * self.sector_size as u64))?;
+                self.device.read_exact(&mut data)?;
+            }
+            self.sectors.insert(number, CachedSector {
+                data: data,
+                dirty: false,
+                last_used: 0,
+            });
+        }
+        let sector = self.sectors.get_mut(&number).unwrap();
+        self.lru.remove(&sector.last_used);
+        self.lru.insert(self.clock, number);
+        sector.last_used = self.clock;
+        Ok(sector)
+    }
+
+    /// Drop the least recently used sector if the cache is full, writing it
+    /// to the device first if it is dirty.
+    fn evict(&mut self) -> io::Result<()> {
+        if self.sectors.len() < self.capacity {
+            return Ok(());
+        }
+        let (last_used, number) = match self.lru.iter().next() {
+            Some((&last_used, &number)) => (last_used, number),
+            None => return Ok(()),
+        };
+        {
+            let sector = &self.sectors[&number];
+            if sector.dirty {
+                self.device.seek(SeekFrom::Start(number * self.sector_size as u64))?;
+                self.device.write_all(&sector.data)?;
+            }
+        }
+        self.lru.remove(&last_used);
+        self.sectors.remove(&number);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SectorCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SectorCache {{ sector_size: {}, cached: {}, dirty: {} }}",
+            self.sector_size, self.sectors.len(), self.dirty_count())
+    }
+}
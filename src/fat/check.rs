@@ -0,0 +1,535 @@
+use std::collections::{BTreeSet,HashMap,HashSet};
+use std::error;
+use std::fmt;
+
+use fat;
+use fat::{Dir,DirEntry,Image,RootEntry};
+
+// Characters that may not appear in a short name.
+const INVALID_NAME_CHARS: &'static [u8] = b"\"*+,./:;<=>?[\\]|";
+
+// Attribute bits that no entry should have set.
+const RESERVED_ATTRIBUTES: u8 = 0xc0;
+
+/// How serious a problem found by `Image::check` is.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Severity {
+    /// Worth knowing, but nothing is wrong with the filesystem.
+    Info,
+    /// Space is wasted or metadata is off, but no data is at risk.
+    Warning,
+    /// The filesystem is inconsistent and data may be lost or corrupted.
+    Error,
+}
+
+/// A problem found by `Image::check`. Paths are absolute within the image
+/// and FATs are numbered from 0, as for `Image::fat_copy`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Problem {
+    /// FAT `fat` differs from the active FAT in `entries` entries. This is
+    /// expected when FAT32 mirroring is disabled.
+    FatMismatch { fat: usize, entries: usize, mirrored: bool },
+    /// `cluster` belongs to the chains of both `first` and `second`.
+    CrossLink { cluster: u32, first: String, second: String },
+    /// The chain of `path` continues from `cluster` to `value`, which is
+    /// earlier in the same chain.
+    ChainLoop { path: String, cluster: u32, value: u32 },
+    /// The chain of `path` continues from `cluster` to `value`, which is a
+    /// free, bad or reserved cluster or lies outside the data area.
+    BadChain { path: String, cluster: u32, value: u32 },
+    /// The file at `path` has `clusters` clusters but its size of
+    /// `file_size` bytes needs `expected`.
+    SizeMismatch { path: String, file_size: u32, clusters: usize, expected: usize },
+    /// A chain of `clusters` clusters starting at `first_cluster` is in use
+    /// but no directory entry refers to it.
+    LostChain { first_cluster: u32, clusters: usize },
+    /// The directory entry at `path` can't be valid.
+    InvalidEntry { path: String, reason: String },
+    /// The `.` or `..` entry of the directory at `path` is missing or wrong.
+    BadDotEntry { path: String, reason: String },
+}
+
+/// The result of checking a filesystem.
+#[derive(Clone,Debug)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+    /// Number of files found by walking the directory tree.
+    pub files: usize,
+    /// Number of directories found, not counting the root directory.
+    pub dirs: usize,
+    /// Number of clusters belonging to files and directories.
+    pub used_clusters: usize,
+}
+
+#[test]
+fn test_check() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir("DIR").unwrap();
+    image.write_file("DIR/A.TXT".to_string(), &[1; 1500]).unwrap();
+    image.write_file("B.TXT".to_string(), &[2; 600]).unwrap();
+    image.write_file("C.TXT".to_string(), &[3; 600]).unwrap();
+    let report = image.check().unwrap();
+    assert_eq!(report.problems, vec![]);
+    assert!(report.is_clean());
+    assert_eq!((report.files, report.dirs, report.used_clusters), (3, 1, 8));
+
+    let a = image.get_file_entry("DIR/A.TXT".to_string()).unwrap().entry_cluster();
+    let b = image.get_file_entry("B.TXT".to_string()).unwrap().entry_cluster();
+    let c = image.get_file_entry("C.TXT".to_string()).unwrap().entry_cluster();
+    let lost = image.allocate_clusters(2).unwrap();
+    image.set_fat_entry(b + 1, a + 1);
+    image.set_fat_entry(c, 0xff0);
+    let report = image.check().unwrap();
+    assert_eq!(report.problems, vec![
+        Problem::SizeMismatch {
+            path: "/B.TXT".to_string(), file_size: 600, clusters: 4, expected: 2 },
+        Problem::BadChain { path: "/C.TXT".to_string(), cluster: c, value: 0xff0 },
+        Problem::SizeMismatch {
+            path: "/C.TXT".to_string(), file_size: 600, clusters: 1, expected: 2 },
+        Problem::CrossLink {
+            cluster: a + 1, first: "/B.TXT".to_string(), second: "/DIR/A.TXT".to_string() },
+        Problem::LostChain { first_cluster: c + 1, clusters: 1 },
+        Problem::LostChain { first_cluster: lost[0], clusters: 2 },
+    ]);
+    assert_eq!((report.count(Severity::Error), report.count(Severity::Warning)), (3, 3));
+
+    // Damage the second FAT and the `..` entry of DIR on disk.
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir("DIR").unwrap();
+    let mut disk = ::std::io::Cursor::new(Vec::new());
+    image.save_to(&mut disk).unwrap();
+    let bpb = image.bios_parameter();
+    let fat_1 = (bpb.reserved_sectors as usize + bpb.sectors_per_fat as usize) * 512;
+    disk.get_mut()[fat_1 + 6] = 0x55;
+    disk.get_mut()[bpb.data_start_sector() * 512 + 32 + 26] = 7;
+    let problems = Image::from_device(disk).unwrap().check().unwrap().problems;
+    assert_eq!(problems, vec![
+        Problem::FatMismatch { fat: 1, entries: 1, mirrored: true },
+        Problem::BadDotEntry {
+            path: "/DIR".to_string(),
+            reason: ".. points at cluster 7 instead of 0".to_string(),
+        },
+    ]);
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Problem {
+    pub fn severity(&self) -> Severity {
+        match *self {
+            Problem::FatMismatch { mirrored: false, .. } => Severity::Info,
+            Problem::SizeMismatch { clusters, expected, .. } if clusters > expected =>
+                Severity::Warning,
+            Problem::LostChain { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::FatMismatch { fat, entries, mirrored } => {
+                write!(f, "FAT {} differs from the active FAT in {} entries",
+                    fat, entries)?;
+                if !mirrored {
+                    write!(f, " (mirroring is disabled)")?;
+                }
+                Ok(())
+            },
+            Problem::CrossLink { cluster, ref first, ref second } =>
+                write!(f, "{} and {} are cross-linked at cluster {}",
+                    first, second, cluster),
+            Problem::ChainLoop { ref path, cluster, value } =>
+                write!(f, "{}: cluster {} loops back to cluster {}", path, cluster, value),
+            Problem::BadChain { ref path, cluster, value } =>
+                write!(f, "{}: cluster {} points to {}, which is not a usable cluster",
+                    path, cluster, value),
+            Problem::SizeMismatch { ref path, file_size, clusters, expected } =>
+                write!(f, "{}: size of {} bytes needs {} clusters, chain has {}",
+                    path, file_size, expected, clusters),
+            Problem::LostChain { first_cluster, clusters } =>
+                write!(f, "lost chain of {} clusters starting at cluster {}",
+                    clusters, first_cluster),
+            Problem::InvalidEntry { ref path, ref reason } =>
+                write!(f, "{}: invalid entry: {}", path, reason),
+            Problem::BadDotEntry { ref path, ref reason } =>
+                write!(f, "{}: {}", path, reason),
+        }
+    }
+}
+
+impl CheckReport {
+    /// Number of problems of the given severity.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.problems.iter().filter(|p| p.severity() == severity).count()
+    }
+
+    /// Whether nothing worse than informational problems was found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.iter().all(|p| p.severity() == Severity::Info)
+    }
+}
+
+/// A directory waiting to be checked.
+struct PendingDir {
+    path: String,
+    cluster: u32,
+    parent_cluster: u32,
+}
+
+/// State of a check in progress.
+struct Checker<'a> {
+    image: &'a Image,
+    report: CheckReport,
+    /// Path of the file or directory each cluster in use belongs to.
+    owners: HashMap<u32, String>,
+}
+
+impl Image {
+    /// Check the filesystem for inconsistencies: FAT copies that differ,
+    /// broken, looping, cross-linked and lost cluster chains, files whose
+    /// size doesn't match their chain, and invalid directory entries. The
+    /// image is not modified.
+    pub fn check(&self) -> Result<CheckReport, Box<error::Error>> {
+        let mut checker = Checker {
+            image: self,
+            report: CheckReport {
+                problems: Vec::new(),
+                files: 0,
+                dirs: 0,
+                used_clusters: 0,
+            },
+            owners: HashMap::new(),
+        };
+        checker.check_fats()?;
+        checker.check_tree()?;
+        checker.find_lost_chains();
+        checker.report.used_clusters = checker.owners.len();
+        Ok(checker.report)
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn problem(&mut self, problem: Problem) {
+        self.report.problems.push(problem);
+    }
+
+    /// Whether `cluster_num` is a data cluster within the data area.
+    fn in_data_area(&self, cluster_num: u32) -> bool {
+        fat::cluster_num_is_valid(self.image.fat_type(), cluster_num)
+            && (cluster_num as usize) < self.image.cluster_count() + 2
+    }
+
+    /// Compare every FAT copy with the active FAT.
+    fn check_fats(&mut self) -> Result<(), Box<error::Error>> {
+        let bpb = self.image.bios_parameter();
+        let fat_type = self.image.fat_type();
+        let entries = self.image.cluster_count() + 2;
+        let active = bpb.active_fat();
+        let active_fat = self.image.fat_copy(active)?;
+        for index in 0 .. bpb.fat_count as usize {
+            if index == active {
+                continue;
+            }
+            let copy = self.image.fat_copy(index)?;
+            let differing = (0 .. entries as u32)
+                .filter(|&i| fat_type.read_entry(&copy, i)
+                    != fat_type.read_entry(&active_fat, i))
+                .count();
+            if differing > 0 {
+                self.problem(Problem::FatMismatch {
+                    fat: index,
+                    entries: differing,
+                    mirrored: bpb.fat_mirroring(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow the chain starting at `first_cluster`, reporting where it
+    /// breaks or loops, and claim its clusters for `path`. Returns the
+    /// clusters of the chain up to the first problem.
+    fn claim_chain(&mut self, path: &str, first_cluster: u32) -> Vec<u32> {
+        let fat_type = self.image.fat_type();
+        let mut chain = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cross_linked = false;
+        let mut cluster_num = first_cluster;
+        loop {
+            if !seen.insert(cluster_num) {
+                self.problem(Problem::ChainLoop {
+                    path: path.to_string(),
+                    cluster: chain[chain.len() - 1],
+                    value: cluster_num,
+                });
+                break;
+            }
+            chain.push(cluster_num);
+            match self.owners.get(&cluster_num).cloned() {
+                // Only the first shared cluster is reported; the rest of
+                // the chain is usually shared as well.
+                Some(owner) => if !cross_linked {
+                    cross_linked = true;
+                    self.problem(Problem::CrossLink {
+                        cluster: cluster_num,
+                        first: owner,
+                        second: path.to_string(),
+                    });
+                },
+                None => {
+                    self.owners.insert(cluster_num, path.to_string());
+                },
+            }
+
+            let next = self.image.get_fat_entry(cluster_num);
+            if fat_type.is_end_of_chain(next) {
+                break;
+            }
+            if !self.in_data_area(next) {
+                self.problem(Problem::BadChain {
+                    path: path.to_string(),
+                    cluster: cluster_num,
+                    value: next,
+                });
+                break;
+            }
+            cluster_num = next;
+        }
+        chain
+    }
+
+    /// Walk the directory tree from the root, checking every entry.
+    fn check_tree(&mut self) -> Result<(), Box<error::Error>> {
+        let root = self.image.root_dir_location();
+        if let Dir::Chain(root_cluster) = root {
+            if self.in_data_area(root_cluster) {
+                self.claim_chain("/", root_cluster);
+            } else {
+                self.problem(Problem::InvalidEntry {
+                    path: "/".to_string(),
+                    reason: format!("root cluster {} is outside the data area",
+                        root_cluster),
+                });
+                return Ok(());
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut pending = vec![PendingDir {
+            path: String::new(),
+            cluster: 0,
+            parent_cluster: 0,
+        }];
+        while let Some(dir) = pending.pop() {
+            let location = match dir.cluster {
+                0 => root,
+                cluster_num => Dir::Chain(cluster_num),
+            };
+            let entries = self.image.read_dir(location)?;
+            if dir.cluster == 0 {
+                self.check_root_entries(&entries);
+            } else {
+                self.check_dot_entries(&dir, &entries);
+            }
+
+            for dir_entry in entries.iter() {
+                let entry = &dir_entry.entry;
+                if is_dot_entry(entry) || entry.is_volume_label() && dir.cluster == 0 {
+                    continue;
+                }
+                let path = format!("{}/{}", dir.path, dir_entry.name);
+                if let Some(reason) = self.entry_problem(entry) {
+                    self.problem(Problem::InvalidEntry { path: path, reason: reason });
+                    continue;
+                }
+
+                let first_cluster = entry.entry_cluster();
+                let chain = if first_cluster == 0 {
+                    Vec::new()
+                } else {
+                    self.claim_chain(&path, first_cluster)
+                };
+                if entry.is_subdir() {
+                    self.report.dirs += 1;
+                    if visited.insert(first_cluster) {
+                        pending.push(PendingDir {
+                            path: path,
+                            cluster: first_cluster,
+                            parent_cluster: dir.cluster,
+                        });
+                    }
+                } else {
+                    self.report.files += 1;
+                    let cluster_size = self.image.cluster_size();
+                    let expected = (entry.file_size as usize + cluster_size - 1)
+                        / cluster_size;
+                    if chain.len() != expected {
+                        self.problem(Problem::SizeMismatch {
+                            path: path,
+                            file_size: entry.file_size,
+                            clusters: chain.len(),
+                            expected: expected,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Why `entry` can't be a valid file or directory entry, if it can't.
+    fn entry_problem(&self, entry: &RootEntry) -> Option<String> {
+        let mut short_name = entry.filename.to_vec();
+        short_name.extend(entry.extension.iter());
+        if short_name[0] == ' ' as u8 {
+            return Some("name starts with a space".to_string());
+        }
+        // 0x05 stands for a leading 0xe5, which would mark the slot free.
+        let bad_char = short_name.iter().enumerate().any(|(i, &c)| {
+            (c < 0x20 && !(i == 0 && c == 0x05)) || INVALID_NAME_CHARS.contains(&c)
+        });
+        if bad_char {
+            return Some(format!("name {:?} contains invalid characters",
+                String::from_utf8_lossy(&short_name)));
+        }
+
+        let attributes = entry.attributes();
+        if attributes & RESERVED_ATTRIBUTES != 0 {
+            return Some(format!("reserved attribute bits set in {:#04x}", attributes));
+        }
+        if entry.is_volume_label() {
+            return Some("volume label outside the root directory".to_string());
+        }
+
+        let first_cluster = entry.entry_cluster();
+        if first_cluster != 0 && !self.in_data_area(first_cluster) {
+            return Some(format!("first cluster {} is outside the data area",
+                first_cluster));
+        }
+        if entry.is_subdir() {
+            if first_cluster == 0 {
+                return Some("directory has no clusters".to_string());
+            }
+            if entry.file_size != 0 {
+                return Some(format!("directory has a size of {} bytes",
+                    entry.file_size));
+            }
+        }
+        None
+    }
+
+    /// The root directory has no `.` or `..` entries and at most one
+    /// volume label.
+    fn check_root_entries(&mut self, entries: &[DirEntry]) {
+        for dir_entry in entries.iter().filter(|d| is_dot_entry(&d.entry)) {
+            self.problem(Problem::BadDotEntry {
+                path: "/".to_string(),
+                reason: format!("root directory has a {} entry", dir_entry.name),
+            });
+        }
+        let labels = entries.iter()
+            .filter(|d| d.entry.is_volume_label())
+            .count();
+        if labels > 1 {
+            self.problem(Problem::InvalidEntry {
+                path: "/".to_string(),
+                reason: format!("{} volume labels", labels),
+            });
+        }
+    }
+
+    /// A subdirectory starts with `.` pointing at itself and `..` pointing
+    /// at its parent, with 0 standing for the root directory.
+    fn check_dot_entries(&mut self, dir: &PendingDir, entries: &[DirEntry]) {
+        let root_cluster = match self.image.root_dir_location() {
+            Dir::Chain(cluster_num) => cluster_num,
+            Dir::Root => 0,
+        };
+        let expected = [
+            (".", dir.cluster),
+            ("..", dir.parent_cluster),
+        ];
+        for (index, &(name, cluster_num)) in expected.iter().enumerate() {
+            let found = entries.iter()
+                .find(|d| d.index == index as u16)
+                .map(|d| &d.entry);
+            let reason = match found {
+                Some(entry) if entry.filename().ok() != Some(name.to_string())
+                    || !entry.is_subdir() =>
+                    format!("entry {} is not a {} directory entry", index, name),
+                // Some systems point `..` at the FAT32 root cluster rather
+                // than 0.
+                Some(entry) if entry.entry_cluster() != cluster_num
+                    && !(cluster_num == 0 && entry.entry_cluster() == root_cluster) =>
+                    format!("{} points at cluster {} instead of {}",
+                        name, entry.entry_cluster(), cluster_num),
+                Some(_) => continue,
+                None => format!("missing {} entry", name),
+            };
+            self.problem(Problem::BadDotEntry {
+                path: dir.path.clone(),
+                reason: reason,
+            });
+        }
+    }
+
+    /// Report chains of clusters that are in use in the FAT but belong to
+    /// no file or directory.
+    fn find_lost_chains(&mut self) {
+        let fat_type = self.image.fat_type();
+        let lost = (2 .. self.image.cluster_count() as u32 + 2)
+            .filter(|cluster_num| !self.owners.contains_key(cluster_num))
+            .filter(|&cluster_num| {
+                let value = self.image.get_fat_entry(cluster_num);
+                value != 0 && value != fat_type.bad_cluster()
+            })
+            .collect::<BTreeSet<u32>>();
+        let targets = lost.iter()
+            .map(|&cluster_num| self.image.get_fat_entry(cluster_num))
+            .collect::<HashSet<u32>>();
+
+        // Chains are followed from clusters nothing else points to first,
+        // then from whatever is left, which can only be loops.
+        let heads = lost.iter()
+            .cloned()
+            .filter(|cluster_num| !targets.contains(cluster_num))
+            .chain(lost.iter().cloned())
+            .collect::<Vec<u32>>();
+        let mut found = HashSet::new();
+        for first_cluster in heads {
+            let mut clusters = 0;
+            let mut cluster_num = first_cluster;
+            while lost.contains(&cluster_num) && found.insert(cluster_num) {
+                clusters += 1;
+                cluster_num = self.image.get_fat_entry(cluster_num);
+            }
+            if clusters > 0 {
+                self.problem(Problem::LostChain {
+                    first_cluster: first_cluster,
+                    clusters: clusters,
+                });
+            }
+        }
+    }
+}
+
+/// Whether `entry` is the `.` or `..` entry of a directory.
+fn is_dot_entry(entry: &RootEntry) -> bool {
+    entry.filename[0] == '.' as u8
+}
@@ -0,0 +1,131 @@
+use std::cmp;
+use std::io;
+use std::io::{Read,Write,Seek,SeekFrom};
+
+/// Storage holding a FAT filesystem: a `File`, a `Cursor<Vec<u8>>`, a block
+/// device opened by the caller or anything else that can read, write and
+/// seek.
+pub trait BlockDevice: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> BlockDevice for T {}
+
+/// Length in bytes of a seekable device. The position is left at the start.
+pub fn device_len<D: Seek>(device: &mut D) -> io::Result<u64> {
+    let len = device.seek(SeekFrom::End(0))?;
+    device.seek(SeekFrom::Start(0))?;
+    Ok(len)
+}
+
+/// A window of `length` bytes starting at `start` in another device, such
+/// as a filesystem inside a partition or embedded in another container.
+#[derive(Debug)]
+pub struct DeviceSlice<D> {
+    device: D,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+#[test]
+fn test_device_slice() {
+    let mut data = io::Cursor::new((0 .. 16).collect::<Vec<u8>>());
+    {
+        let mut slice = DeviceSlice::new(&mut data, 4, 8);
+        let mut buf = [0; 4];
+        slice.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6, 7]);
+
+        assert_eq!(device_len(&mut slice).unwrap(), 8);
+        slice.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(slice.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[10, 11]);
+
+        slice.seek(SeekFrom::Start(6)).unwrap();
+        assert!(slice.write_all(&[0xff; 4]).is_err());
+    }
+    assert_eq!(&data.get_ref()[8 .. 13], &[8, 9, 0xff, 0xff, 12]);
+}
+
+#[allow(dead_code)]
+impl<D: Seek> DeviceSlice<D> {
+    pub fn new(device: D, start: u64, length: u64) -> DeviceSlice<D> {
+        DeviceSlice {
+            device: device,
+            start: start,
+            length: length,
+            position: 0,
+        }
+    }
+
+    /// Give back the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Bytes that can be transferred from the current position, at most
+    /// `wanted`.
+    fn available(&self, wanted: usize) -> usize {
+        cmp::min(wanted as u64, self.length.saturating_sub(self.position)) as usize
+    }
+
+    fn seek_device(&mut self) -> io::Result<()> {
+        self.device.seek(SeekFrom::Start(self.start + self.position))?;
+        Ok(())
+    }
+}
+
+impl<D: Read + Seek> Read for DeviceSlice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        self.seek_device()?;
+        let read = self.device.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<D: Write + Seek> Write for DeviceSlice<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        self.seek_device()?;
+        let written = self.device.write(&buf[..len])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+}
+
+impl<D: Seek> Seek for DeviceSlice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_by(self.length, offset),
+            SeekFrom::Current(offset) => offset_by(self.position, offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "seek to a negative position")),
+        }
+    }
+}
+
+fn offset_by(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
@@ -0,0 +1,73 @@
+use fat::RootEntry;
+
+/// Location of a directory's entries within the image.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum Dir {
+    /// The fixed size root directory region used by FAT12 and FAT16.
+    Root,
+    /// A directory stored in the cluster chain starting at this cluster.
+    Chain(u32),
+}
+
+/// A directory entry together with its name and the location of its slots.
+#[derive(Clone,Debug)]
+pub struct DirEntry {
+    /// Long filename if the entry has a valid one, the short name otherwise.
+    pub name: String,
+    /// Directory containing the entry.
+    pub dir: Dir,
+    /// Index of the entry's slot within the directory.
+    pub index: u16,
+    /// Index of the first long filename slot, or `index` if there are none.
+    pub lfn_start: u16,
+    pub entry: RootEntry,
+}
+
+/// Split a path like `BOOT/GRUB/MENU.LST` into its components. Both `/`
+/// and `\` are accepted as separators and empty components are dropped.
+pub fn split_path(path: &str) -> Vec<&str> {
+    path.split(|c| c == '/' || c == '\\')
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+#[test]
+fn test_split_path() {
+    assert_eq!(split_path("/BOOT//GRUB\\MENU.LST"), vec!["BOOT", "GRUB", "MENU.LST"]);
+    assert!(split_path("/").is_empty());
+}
+
+#[test]
+fn test_lookup_path() {
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir_all("A/B").unwrap();
+    image.write_file("A/B/C.TXT".to_string(), b"c").unwrap();
+
+    let a = image.lookup("A").unwrap().entry.entry_cluster();
+    let b = image.lookup("A/B").unwrap();
+    assert_eq!(b.dir, Dir::Chain(a));
+    let c = image.lookup("a\\b/c.txt").unwrap();
+    assert_eq!(c.dir, Dir::Chain(b.entry.entry_cluster()));
+    assert_eq!(c.name, "C.TXT");
+    assert_eq!(image.read_file(&c.entry).unwrap(), b"c");
+    assert!(image.lookup("A/C.TXT").is_err());
+    assert!(image.lookup("A/B/C.TXT/D").is_err());
+}
+
+#[test]
+fn test_lookup_dot_components() {
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir_all("A/B").unwrap();
+    image.write_file("A/B/C.TXT".to_string(), b"c").unwrap();
+    let root = image.root_dir_location();
+    let a = Dir::Chain(image.lookup("A").unwrap().entry.entry_cluster());
+
+    assert_eq!(image.lookup_dir(".").unwrap(), root);
+    assert_eq!(image.lookup_dir("A/.").unwrap(), a);
+    assert_eq!(image.lookup_dir("A/B/..").unwrap(), a);
+    assert_eq!(image.lookup_dir("A/B/../..").unwrap(), root);
+    // Going above the root stays at the root.
+    assert_eq!(image.lookup_dir("../A").unwrap(), a);
+    assert_eq!(image.lookup("A/./B/../B/C.TXT").unwrap().name, "C.TXT");
+    assert!(image.lookup("A/..").is_err());
+}
@@ -0,0 +1,97 @@
+extern crate byteorder;
+
+use self::byteorder::{LittleEndian,ByteOrder};
+
+/// The FAT variant, which determines how wide each FAT entry is.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[test]
+fn test_fat_type_thresholds() {
+    assert_eq!(FatType::from_cluster_count(4084), FatType::Fat12);
+    assert_eq!(FatType::from_cluster_count(4085), FatType::Fat16);
+    assert_eq!(FatType::from_cluster_count(65524), FatType::Fat16);
+    assert_eq!(FatType::from_cluster_count(65525), FatType::Fat32);
+}
+
+impl FatType {
+    /// Determine the FAT type from the number of data clusters, using the
+    /// thresholds from the Microsoft FAT specification.
+    pub fn from_cluster_count(clusters: usize) -> FatType {
+        if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// Mask of the bits used by a FAT entry.
+    pub fn entry_mask(&self) -> u32 {
+        match *self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fffffff,
+        }
+    }
+
+    /// Value written to mark the last cluster of a chain.
+    pub fn end_of_chain(&self) -> u32 {
+        self.entry_mask()
+    }
+
+    /// Value marking a cluster as bad.
+    pub fn bad_cluster(&self) -> u32 {
+        self.entry_mask() - 8
+    }
+
+    /// Check whether a FAT entry value marks the end of a chain.
+    pub fn is_end_of_chain(&self, value: u32) -> bool {
+        value >= self.entry_mask() - 7
+    }
+
+    /// Decode the entry for `cluster_num` from the raw bytes of a FAT.
+    pub fn read_entry(&self, fat: &[u8], cluster_num: u32) -> u32 {
+        match *self {
+            FatType::Fat12 => {
+                let offset: usize = cluster_num as usize * 3 / 2;
+                let byte_1: u32 = fat[offset] as u32;
+                let byte_2: u32 = fat[offset + 1] as u32;
+
+                if cluster_num % 2 == 0 { byte_1 | ((byte_2 & 0x0f) << 8) }
+                else                    { (byte_1 >> 4) | (byte_2 << 4) }
+            },
+            FatType::Fat16 => {
+                let offset: usize = cluster_num as usize * 2;
+                LittleEndian::read_u16(&fat[offset..offset + 2]) as u32
+            },
+            FatType::Fat32 => {
+                let offset: usize = cluster_num as usize * 4;
+                LittleEndian::read_u32(&fat[offset..offset + 4]) & 0x0fffffff
+            },
+        }
+    }
+
+    /// Number of FAT entries that fit in `bytes` bytes of FAT.
+    pub fn entries_in(&self, bytes: usize) -> usize {
+        match *self {
+            FatType::Fat12 => bytes * 2 / 3,
+            FatType::Fat16 => bytes / 2,
+            FatType::Fat32 => bytes / 4,
+        }
+    }
+
+    /// Human readable name, e.g. "FAT16".
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        }
+    }
+}
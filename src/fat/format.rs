@@ -0,0 +1,397 @@
+use std::error;
+use std::io;
+use std::path::Path;
+
+use fat::BIOSParam;
+use fat::BlockDevice;
+use fat::DateTime;
+use fat::FatType;
+use fat::Image;
+use fat::FloppyGeometry;
+
+const BYTES_PER_SECTOR: u16 = 512;
+const MIB: u64 = 1024 * 1024;
+
+// Volumes at least this large default to FAT32, like mkfs.fat.
+const FAT32_THRESHOLD: u64 = 512 * MIB;
+
+// Boot code that halts the machine, for images that are not bootable.
+const BOOT_CODE: [u8; 4] = [0xfa, 0xf4, 0xeb, 0xfd];
+
+/// Builds a freshly formatted FAT image. Any parameter that isn't set is
+/// chosen the way mkfs.fat would for a disk of the given size.
+#[derive(Clone,Debug)]
+pub struct ImageBuilder {
+    size: u64,
+    fat_type: Option<FatType>,
+    label: Option<String>,
+    sectors_per_cluster: Option<u8>,
+    root_entries: Option<u16>,
+    fat_count: u8,
+    media_id: u8,
+    volume_id: Option<u32>,
+    time: Option<DateTime>,
+    sectors_per_track: u16,
+    heads: u16,
+    drive_number: u8,
+}
+
+#[test]
+fn test_image_builder_params() {
+    // Floppy sizes are laid out like the floppy, as mkfs.fat does.
+    let bpb = ImageBuilder::new(1440 * 1024).bios_param().unwrap();
+    assert_eq!(bpb.fat_type(), FatType::Fat12);
+    assert_eq!(bpb.sectors, 2880);
+    assert_eq!(bpb.max_roots, 224);
+    assert_eq!(bpb.media_id, 0xf0);
+    assert_eq!((bpb.sectors_per_track, bpb.heads), (18, 2));
+
+    let bpb = ImageBuilder::new(1400 * 1024).bios_param().unwrap();
+    assert_eq!(bpb.media_id, 0xf8);
+    assert_eq!((bpb.sectors_per_track, bpb.heads), (32, 64));
+
+    let bpb = ImageBuilder::new(64 * MIB).bios_param().unwrap();
+    assert_eq!(bpb.fat_type(), FatType::Fat16);
+    assert_eq!(bpb.sectors_per_cluster, 4);
+    assert_eq!(bpb.max_roots, 512);
+
+    let bpb = ImageBuilder::new(64 * MIB)
+        .fat_type(FatType::Fat32).bios_param().unwrap();
+    assert_eq!(bpb.fat_type(), FatType::Fat32);
+    assert_eq!(bpb.reserved_sectors, 32);
+    assert_eq!(bpb.root_cluster, 2);
+
+    assert!(ImageBuilder::new(MIB).fat_type(FatType::Fat32).bios_param().is_err());
+
+    for geometry in ::fat::FLOPPY_GEOMETRIES {
+        let bpb = ImageBuilder::floppy(geometry).bios_param().unwrap();
+        assert_eq!(::fat::FloppyGeometry::matching(&bpb), Some(geometry));
+        assert_eq!(bpb.fat_type(), FatType::Fat12);
+    }
+    let bpb = ImageBuilder::floppy(
+        ::fat::FloppyGeometry::by_name("1.44M").unwrap()).bios_param().unwrap();
+    assert_eq!(bpb.sectors_per_fat, 9);
+}
+
+#[test]
+fn test_reproducible_build() {
+    let time = "2024-01-01 12:34:56".parse::<DateTime>().unwrap();
+    let build = || {
+        let mut image = ImageBuilder::new(1440 * 1024).label("REPRO").time(time)
+            .build().unwrap();
+        image.create_dir_all("A/B").unwrap();
+        image.write_file("A/B/A long file name.txt".to_string(), b"data").unwrap();
+        let mut bytes = Vec::new();
+        image.save_to(&mut bytes).unwrap();
+        (image, bytes)
+    };
+    let (image, first) = build();
+    let (_, second) = build();
+    assert!(first == second);
+
+    assert_eq!(image.bios_parameter().volume_id, 0x3901_140a);
+    let entry = image.get_file_entry("A/B/A long file name.txt".to_string()).unwrap();
+    assert_eq!(entry.created(), Some(time));
+    assert_eq!(entry.modified(), Some(time));
+}
+
+#[allow(dead_code)]
+impl ImageBuilder {
+    /// Start building an image of `size` bytes. A size that matches a
+    /// standard floppy format gets that format's layout, as with `floppy`.
+    pub fn new(size: u64) -> ImageBuilder {
+        let geometry = FloppyGeometry::by_name(&format!("{}K", size / 1024))
+            .filter(|geometry| geometry.size() == size);
+        match geometry {
+            Some(geometry) => ImageBuilder::floppy(geometry),
+            None => ImageBuilder::fixed_disk(size),
+        }
+    }
+
+    /// Start building an image in one of the standard floppy formats.
+    pub fn floppy(geometry: &FloppyGeometry) -> ImageBuilder {
+        ImageBuilder {
+            fat_type: Some(FatType::Fat12),
+            sectors_per_cluster: Some(geometry.sectors_per_cluster),
+            root_entries: Some(geometry.root_entries),
+            media_id: geometry.media_id,
+            sectors_per_track: geometry.sectors_per_track,
+            heads: geometry.heads,
+            drive_number: 0,
+            .. ImageBuilder::fixed_disk(geometry.size())
+        }
+    }
+
+    /// Defaults for a disk that is not a floppy, with the geometry mkfs.fat
+    /// gives disks it knows nothing about.
+    fn fixed_disk(size: u64) -> ImageBuilder {
+        ImageBuilder {
+            size: size,
+            fat_type: None,
+            label: None,
+            sectors_per_cluster: None,
+            root_entries: None,
+            fat_count: 2,
+            media_id: 0xf8,
+            volume_id: None,
+            time: None,
+            sectors_per_track: 32,
+            heads: 64,
+            drive_number: 0x80,
+        }
+    }
+
+    pub fn fat_type(mut self, fat_type: FatType) -> ImageBuilder {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> ImageBuilder {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn sectors_per_cluster(mut self, sectors: u8) -> ImageBuilder {
+        self.sectors_per_cluster = Some(sectors);
+        self
+    }
+
+    pub fn root_entries(mut self, entries: u16) -> ImageBuilder {
+        self.root_entries = Some(entries);
+        self
+    }
+
+    pub fn fat_count(mut self, count: u8) -> ImageBuilder {
+        self.fat_count = count;
+        self
+    }
+
+    pub fn media_id(mut self, media_id: u8) -> ImageBuilder {
+        self.media_id = media_id;
+        self
+    }
+
+    pub fn volume_id(mut self, volume_id: u32) -> ImageBuilder {
+        self.volume_id = Some(volume_id);
+        self
+    }
+
+    /// Use `time` instead of the current time for the volume serial number
+    /// and every timestamp the image gets, so that building the same image
+    /// twice gives identical bytes.
+    pub fn time(mut self, time: DateTime) -> ImageBuilder {
+        self.time = Some(time);
+        self
+    }
+
+    /// Size of the image in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// FAT type to format with: the requested one, or the smallest type
+    /// that suits the image size.
+    fn choose_fat_type(&self) -> FatType {
+        if let Some(fat_type) = self.fat_type {
+            return fat_type;
+        }
+        if self.size >= FAT32_THRESHOLD {
+            FatType::Fat32
+        } else if self.size <= 8 * 4084 * BYTES_PER_SECTOR as u64 {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        }
+    }
+
+    /// Default cluster size in sectors, following the tables in the
+    /// Microsoft FAT specification.
+    fn default_sectors_per_cluster(&self, fat_type: FatType) -> u8 {
+        match fat_type {
+            FatType::Fat12 => 1,
+            FatType::Fat16 => match self.size {
+                s if s <= 128 * MIB => 4,
+                s if s <= 256 * MIB => 8,
+                s if s <= 512 * MIB => 16,
+                s if s <= 1024 * MIB => 32,
+                _ => 64,
+            },
+            FatType::Fat32 => match self.size {
+                s if s <= 260 * MIB => 1,
+                s if s <= 8192 * MIB => 8,
+                s if s <= 16384 * MIB => 16,
+                s if s <= 32768 * MIB => 32,
+                _ => 64,
+            },
+        }
+    }
+
+    /// Compute the BIOS Parameter Block for the image.
+    pub fn bios_param(&self) -> Result<BIOSParam, Box<error::Error>> {
+        let fat_type = self.choose_fat_type();
+        let total_sectors = self.size / BYTES_PER_SECTOR as u64;
+        if total_sectors > u32::max_value() as u64 {
+            return Err(From::from("image is too large for FAT"));
+        }
+        if self.fat_count == 0 {
+            return Err(From::from("image needs at least one FAT"));
+        }
+
+        let mut bpb = BIOSParam::new();
+        bpb.jump = match fat_type {
+            FatType::Fat32 => [0xeb, 0x58, 0x90],
+            _ => [0xeb, 0x3c, 0x90],
+        };
+        bpb.oem_name.copy_from_slice(b"MSWIN4.1");
+        bpb.bytes_per_sector = BYTES_PER_SECTOR;
+        bpb.sectors = total_sectors as u32;
+        bpb.fat_count = self.fat_count;
+        bpb.media_id = self.media_id;
+        match fat_type {
+            FatType::Fat32 => {
+                bpb.reserved_sectors = 32;
+                bpb.max_roots = 0;
+                bpb.root_cluster = 2;
+                bpb.fs_info_sector = 1;
+                bpb.backup_boot_sector = 6;
+            },
+            _ => {
+                bpb.reserved_sectors = 1;
+                bpb.max_roots = self.root_entries.unwrap_or(512);
+            },
+        }
+
+        // Grow the clusters until they fit, unless the size was requested.
+        let mut sectors_per_cluster = self.sectors_per_cluster
+            .unwrap_or(self.default_sectors_per_cluster(fat_type));
+        loop {
+            if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two() {
+                return Err(From::from(format!(
+                    "bad cluster size: {} sectors", sectors_per_cluster)));
+            }
+            bpb.sectors_per_cluster = sectors_per_cluster;
+            bpb.sectors_per_fat = fat_sectors(&bpb, fat_type);
+
+            let actual_type = bpb.fat_type();
+            if actual_type == fat_type {
+                break;
+            }
+            let too_many = actual_type > fat_type;
+            if !too_many || self.sectors_per_cluster.is_some()
+                || sectors_per_cluster >= 128
+            {
+                return Err(From::from(format!(
+                    "{} bytes gives {} clusters of {} bytes, which is \
+                    not valid for {}",
+                    self.size, bpb.clusters(),
+                    sectors_per_cluster as usize * BYTES_PER_SECTOR as usize,
+                    fat_type.name())));
+            }
+            sectors_per_cluster *= 2;
+        }
+
+        bpb.sectors_per_track = self.sectors_per_track;
+        bpb.heads = self.heads;
+        bpb.drive_number = self.drive_number;
+        bpb.boot_signature = 0x29;
+        bpb.volume_id = self.volume_id.unwrap_or_else(|| {
+            volume_id_for(self.time.unwrap_or_else(DateTime::now))
+        });
+        bpb.volume_label = self.label_bytes();
+        bpb.fs_type.copy_from_slice(match fat_type {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        });
+        bpb.boot_code = BOOT_CODE.to_vec();
+        Ok(bpb)
+    }
+
+    /// Volume label padded to 11 bytes, or `NO NAME` if there is none.
+    fn label_bytes(&self) -> [u8; 11] {
+        let mut bytes = [' ' as u8; 11];
+        let label = self.label.clone().unwrap_or("NO NAME".to_string());
+        for (b, c) in bytes.iter_mut().zip(label.to_uppercase().bytes()) {
+            *b = c;
+        }
+        bytes
+    }
+
+    fn check_label(&self) -> Result<(), Box<error::Error>> {
+        if let Some(ref label) = self.label {
+            let bad_char = label.chars().any(|c| {
+                !c.is_ascii() || (c as u32) < 0x20 || "\"*+,./:;<=>?[\\]|".contains(c)
+            });
+            if label.is_empty() || label.len() > 11 || bad_char {
+                return Err(From::from(format!("bad volume label: \"{}\"", label)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Format the image in memory.
+    pub fn build(&self) -> Result<Image, Box<error::Error>> {
+        let device = io::Cursor::new(Vec::new());
+        self.build_on(device)
+    }
+
+    /// Format the image on `device`, growing it to the image size if it is
+    /// shorter. Nothing is written until the image is flushed.
+    pub fn build_on<D: BlockDevice + 'static>(&self, device: D)
+        -> Result<Image, Box<error::Error>>
+    {
+        self.check_label()?;
+        let bpb = self.bios_param()?;
+        let label = match self.label {
+            Some(_) => Some(self.label_bytes()),
+            None => None,
+        };
+        Image::format(Box::new(device), bpb, label, self.time)
+    }
+
+    /// Format the image on `device` and copy the host directory `src` into
+    /// its root directory, as `Image::add_tree` does. Nothing is written
+    /// until the image is flushed.
+    pub fn build_from_dir<P: AsRef<Path>, D: BlockDevice + 'static>(&self, src: P, device: D)
+        -> Result<Image, Box<error::Error>>
+    {
+        let mut image = self.build_on(device)?;
+        image.add_tree(src, "")?;
+        Ok(image)
+    }
+}
+
+/// Volume serial number for a volume formatted at `time`, mixing the date
+/// and time the way DOS FORMAT does.
+fn volume_id_for(time: DateTime) -> u32 {
+    let (date, time) = (time.date, time.time);
+    let centiseconds = time.millisecond as u32 / 10;
+    let high = ((date.month as u32) << 8 | date.day as u32)
+        + ((time.second as u32) << 8 | centiseconds);
+    let low = ((time.hour as u32) << 8 | time.minute as u32) + date.year as u32;
+    (high & 0xffff) << 16 | (low & 0xffff)
+}
+
+/// Number of sectors each FAT needs to cover the clusters left over once
+/// the FATs themselves are accounted for.
+fn fat_sectors(bpb: &BIOSParam, fat_type: FatType) -> u32 {
+    let bytes_per_sector = bpb.bytes_per_sector as u64;
+    let fixed_sectors = bpb.reserved_sectors as u64 + bpb.root_dir_sectors() as u64;
+    let mut sectors_per_fat = 1;
+    loop {
+        let used = fixed_sectors + bpb.fat_count as u64 * sectors_per_fat;
+        let clusters =
+            (bpb.sectors as u64).saturating_sub(used) / bpb.sectors_per_cluster as u64;
+        let entries = clusters + 2;
+        let bytes = match fat_type {
+            FatType::Fat12 => (entries * 3 + 1) / 2,
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        };
+        let needed = (bytes + bytes_per_sector - 1) / bytes_per_sector;
+        if needed <= sectors_per_fat {
+            return sectors_per_fat as u32;
+        }
+        sectors_per_fat = needed;
+    }
+}
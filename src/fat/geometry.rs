@@ -0,0 +1,133 @@
+use fat::BIOSParam;
+
+/// Media descriptor for fixed disks. Floppies each have their own.
+pub const FIXED_DISK_MEDIA_ID: u8 = 0xf8;
+
+/// Layout of a standard floppy disk format.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct FloppyGeometry {
+    pub name: &'static str,
+    pub sectors: u32,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub media_id: u8,
+    pub root_entries: u16,
+    pub sectors_per_cluster: u8,
+}
+
+/// Standard PC floppy formats, all with 512 byte sectors.
+pub const FLOPPY_GEOMETRIES: &'static [FloppyGeometry] = &[
+    FloppyGeometry { name: "160K", sectors: 320, sectors_per_track: 8, heads: 1,
+        media_id: 0xfe, root_entries: 64, sectors_per_cluster: 1 },
+    FloppyGeometry { name: "180K", sectors: 360, sectors_per_track: 9, heads: 1,
+        media_id: 0xfc, root_entries: 64, sectors_per_cluster: 1 },
+    FloppyGeometry { name: "320K", sectors: 640, sectors_per_track: 8, heads: 2,
+        media_id: 0xff, root_entries: 112, sectors_per_cluster: 2 },
+    FloppyGeometry { name: "360K", sectors: 720, sectors_per_track: 9, heads: 2,
+        media_id: 0xfd, root_entries: 112, sectors_per_cluster: 2 },
+    FloppyGeometry { name: "720K", sectors: 1440, sectors_per_track: 9, heads: 2,
+        media_id: 0xf9, root_entries: 112, sectors_per_cluster: 2 },
+    FloppyGeometry { name: "1.2M", sectors: 2400, sectors_per_track: 15, heads: 2,
+        media_id: 0xf9, root_entries: 224, sectors_per_cluster: 1 },
+    FloppyGeometry { name: "1.44M", sectors: 2880, sectors_per_track: 18, heads: 2,
+        media_id: 0xf0, root_entries: 224, sectors_per_cluster: 1 },
+    FloppyGeometry { name: "DMF", sectors: 3360, sectors_per_track: 21, heads: 2,
+        media_id: 0xf0, root_entries: 16, sectors_per_cluster: 4 },
+    FloppyGeometry { name: "2.88M", sectors: 5760, sectors_per_track: 36, heads: 2,
+        media_id: 0xf0, root_entries: 240, sectors_per_cluster: 2 },
+];
+
+#[test]
+fn test_floppy_geometry() {
+    assert_eq!(FloppyGeometry::by_name("1.44m").unwrap().sectors, 2880);
+    assert_eq!(FloppyGeometry::by_name("1440K").unwrap().name, "1.44M");
+    assert_eq!(FloppyGeometry::by_name("1680K").unwrap().name, "DMF");
+    assert!(FloppyGeometry::by_name("1.5M").is_none());
+
+    let mut bpb = BIOSParam::new();
+    bpb.bytes_per_sector = 512;
+    bpb.sectors = 1440;
+    bpb.sectors_per_track = 9;
+    bpb.heads = 2;
+    bpb.media_id = 0xf9;
+    bpb.max_roots = 112;
+    assert_eq!(FloppyGeometry::matching(&bpb).unwrap().name, "720K");
+    assert!(geometry_warning(&bpb).is_none());
+
+    bpb.sectors_per_track = 18;
+    assert!(FloppyGeometry::matching(&bpb).is_none());
+    assert!(geometry_warning(&bpb).is_some());
+
+    bpb.sectors = 2000;
+    assert!(geometry_warning(&bpb).is_some());
+    bpb.hidden_sectors = 63;
+    assert!(geometry_warning(&bpb).is_none());
+
+    // A superfloppy shares the removable media byte but isn't floppy sized.
+    bpb.hidden_sectors = 0;
+    bpb.sectors = 200000;
+    bpb.media_id = 0xf0;
+    assert!(geometry_warning(&bpb).is_none());
+
+    bpb.sectors = 1440;
+    bpb.media_id = FIXED_DISK_MEDIA_ID;
+    assert!(geometry_warning(&bpb).is_none());
+}
+
+impl FloppyGeometry {
+    /// Size of the disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.sectors as u64 * 512
+    }
+
+    /// Look up a format by name, like `1.44M` or `DMF`, or by its size in
+    /// kilobytes, like `1440K`.
+    pub fn by_name(name: &str) -> Option<&'static FloppyGeometry> {
+        let name = name.to_uppercase();
+        FLOPPY_GEOMETRIES.iter().find(|geometry| {
+            geometry.name == name || format!("{}K", geometry.sectors / 2) == name
+        })
+    }
+
+    /// The standard format `bpb` describes, if any.
+    pub fn matching(bpb: &BIOSParam) -> Option<&'static FloppyGeometry> {
+        FLOPPY_GEOMETRIES.iter().find(|geometry| {
+            bpb.bytes_per_sector == 512
+                && bpb.sectors == geometry.sectors
+                && bpb.sectors_per_track == geometry.sectors_per_track
+                && bpb.heads == geometry.heads
+                && bpb.media_id == geometry.media_id
+                && bpb.max_roots == geometry.root_entries
+        })
+    }
+}
+
+/// Describe why `bpb` looks like a floppy but matches no standard format.
+/// It looks like one if it has the size of a standard format, or if it is
+/// no larger than the largest format and starts the disk. Fixed disks and
+/// other removable media, such as superfloppies, never get a warning.
+pub fn geometry_warning(bpb: &BIOSParam) -> Option<String> {
+    if bpb.media_id == FIXED_DISK_MEDIA_ID || FloppyGeometry::matching(bpb).is_some() {
+        return None;
+    }
+    let same_size = FLOPPY_GEOMETRIES.iter()
+        .find(|geometry| geometry.sectors == bpb.sectors);
+    let largest = FLOPPY_GEOMETRIES.iter()
+        .map(|geometry| geometry.sectors)
+        .max()
+        .unwrap_or(0);
+    match same_size {
+        Some(geometry) => Some(format!(
+            "image is the size of a {} floppy, but has {} sectors per track, \
+            {} heads, media {:#x} and {} root entries instead of {}, {}, {:#x} \
+            and {}",
+            geometry.name, bpb.sectors_per_track, bpb.heads, bpb.media_id,
+            bpb.max_roots, geometry.sectors_per_track, geometry.heads,
+            geometry.media_id, geometry.root_entries)),
+        None if bpb.hidden_sectors == 0 && bpb.sectors <= largest => Some(format!(
+            "image has floppy media {:#x} but its {} sectors match no \
+            standard floppy format",
+            bpb.media_id, bpb.sectors)),
+        None => None,
+    }
+}
@@ -0,0 +1,307 @@
+extern crate byteorder;
+
+use std::error;
+use std::fmt;
+use std::io::{Read,Seek,SeekFrom};
+
+use self::byteorder::{LittleEndian,ByteOrder};
+
+use fat::{Partition,PartitionType};
+
+const SIGNATURE: &'static [u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+// Larger entries than this mean a corrupt header, not a future revision.
+const MAX_ENTRY_SIZE: usize = 4096;
+
+// Sector sizes to look for the header with, in order.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+// More entries than this means a corrupt header rather than a real table.
+const MAX_ENTRIES: u32 = 1024;
+
+// Partition attribute telling legacy BIOSes to boot the partition.
+const LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// A GUID, stored in the mixed endian layout GPT uses on disk.
+#[derive(Clone,Copy,PartialEq,Eq,Hash)]
+pub struct Guid(pub [u8; 16]);
+
+pub const ESP_GUID: Guid = Guid([
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
+    0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+pub const BASIC_DATA_GUID: Guid = Guid([
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44,
+    0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+
+/// Names of common partition type GUIDs.
+const KNOWN_TYPES: &'static [(&'static str, &'static str)] = &[
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System"),
+    ("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "Basic data"),
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+    ("E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "Microsoft reserved"),
+    ("DE94BBA4-06D1-4D40-A16A-BFD50179D6AC", "Windows recovery"),
+    ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "Linux filesystem"),
+    ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+    ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+    ("A19D880F-05FC-4D3B-A006-743F0F84911E", "Linux RAID"),
+    ("BC13C2FF-59E6-4262-A352-B275FD6F7172", "Linux extended boot"),
+    ("48465300-0000-11AA-AA11-00306543ECAC", "Apple HFS+"),
+    ("7C3457EF-0000-11AA-AA11-00306543ECAC", "Apple APFS"),
+];
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+}
+
+#[test]
+fn test_read_gpt() {
+    let mut disk = vec![0; 128 * 512];
+    {
+        let entries = &mut disk[2 * 512 .. 34 * 512];
+        entries[0..16].copy_from_slice(&ESP_GUID.0);
+        entries[16] = 1;
+        LittleEndian::write_u64(&mut entries[32..40], 40);
+        LittleEndian::write_u64(&mut entries[40..48], 79);
+        for (i, c) in "EFI".encode_utf16().enumerate() {
+            LittleEndian::write_u16(&mut entries[56 + 2 * i .. 58 + 2 * i], c);
+        }
+        entries[128..144].copy_from_slice(&BASIC_DATA_GUID.0);
+        LittleEndian::write_u64(&mut entries[160..168], 80);
+        LittleEndian::write_u64(&mut entries[168..176], 95);
+        LittleEndian::write_u64(&mut entries[176..184], LEGACY_BIOS_BOOTABLE);
+    }
+    let entries_crc = crc32(&disk[2 * 512 .. 34 * 512]);
+    {
+        let header = &mut disk[512 .. 512 + MIN_HEADER_SIZE];
+        header[0..8].copy_from_slice(SIGNATURE);
+        LittleEndian::write_u32(&mut header[8..12], 0x10000);
+        LittleEndian::write_u32(&mut header[12..16], MIN_HEADER_SIZE as u32);
+        LittleEndian::write_u64(&mut header[24..32], 1);
+        LittleEndian::write_u64(&mut header[32..40], 127);
+        LittleEndian::write_u64(&mut header[40..48], 34);
+        LittleEndian::write_u64(&mut header[48..56], 94);
+        LittleEndian::write_u64(&mut header[72..80], 2);
+        LittleEndian::write_u32(&mut header[80..84], 128);
+        LittleEndian::write_u32(&mut header[84..88], 128);
+        LittleEndian::write_u32(&mut header[88..92], entries_crc);
+        let header_crc = crc32(header);
+        LittleEndian::write_u32(&mut header[16..20], header_crc);
+    }
+
+    let partitions = read_gpt(&mut ::std::io::Cursor::new(&disk[..])).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].number, 1);
+    assert_eq!(partitions[0].start, 40 * 512);
+    assert_eq!(partitions[0].length, 40 * 512);
+    assert_eq!(partitions[0].name, "EFI");
+    assert!(partitions[0].is_esp());
+    assert_eq!(partitions[1].number, 2);
+    assert!(partitions[1].bootable);
+    assert_eq!(format!("{}", partitions[1].partition_type), "Basic data");
+
+    // A damaged table is rejected rather than trusted.
+    disk[2 * 512 + 40] = 0x50;
+    assert!(read_gpt(&mut ::std::io::Cursor::new(&disk[..])).is_err());
+
+    // So are tables whose CRCs match but whose sizes and sectors are
+    // impossible, without allocating or overflowing.
+    fn seal(disk: &mut [u8]) {
+        let entries_crc = crc32(&disk[2 * 512 .. 34 * 512]);
+        let header = &mut disk[512 .. 512 + MIN_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[88..92], entries_crc);
+        LittleEndian::write_u32(&mut header[16..20], 0);
+        let header_crc = crc32(header);
+        LittleEndian::write_u32(&mut header[16..20], header_crc);
+    }
+    seal(&mut disk);
+    assert_eq!(read_gpt(&mut ::std::io::Cursor::new(&disk[..])).unwrap().len(), 2);
+    LittleEndian::write_u32(&mut disk[512 + 84 .. 512 + 88], 0xffff_fff8);
+    seal(&mut disk);
+    assert!(read_gpt(&mut ::std::io::Cursor::new(&disk[..])).is_err());
+    LittleEndian::write_u32(&mut disk[512 + 84 .. 512 + 88], 128);
+    LittleEndian::write_u64(&mut disk[512 + 72 .. 512 + 80], u64::max_value() / 256);
+    seal(&mut disk);
+    assert!(read_gpt(&mut ::std::io::Cursor::new(&disk[..])).is_err());
+    LittleEndian::write_u64(&mut disk[512 + 72 .. 512 + 80], 2);
+    LittleEndian::write_u64(&mut disk[2 * 512 + 40 .. 2 * 512 + 48], u64::max_value());
+    seal(&mut disk);
+    assert!(read_gpt(&mut ::std::io::Cursor::new(&disk[..])).is_err());
+}
+
+/// CRC-32 as used by GPT (IEEE 802.3, reflected).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+impl Guid {
+    /// Name of the partition type this GUID stands for, if it is known.
+    pub fn type_name(&self) -> Option<&'static str> {
+        let text = self.to_string();
+        KNOWN_TYPES.iter()
+            .find(|&&(guid, _)| guid == text)
+            .map(|&(_, name)| name)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            LittleEndian::read_u32(&b[0..4]), LittleEndian::read_u16(&b[4..6]),
+            LittleEndian::read_u16(&b[6..8]), b[8], b[9])?;
+        for byte in &b[10..16] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+/// The fields of a GPT header needed to find the partition entries.
+struct Header {
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Read and validate the header at `lba`.
+fn read_header<D: Read + Seek>(device: &mut D, sector_size: u64, lba: u64)
+    -> Result<Header, Box<error::Error>>
+{
+    let mut sector = vec![0; sector_size as usize];
+    device.seek(SeekFrom::Start(lba_offset(lba, sector_size)?))?;
+    device.read_exact(&mut sector)?;
+    if &sector[0..8] != SIGNATURE {
+        return Err(From::from(format!("no GPT header at sector {}", lba)));
+    }
+
+    let header_size = LittleEndian::read_u32(&sector[12..16]) as usize;
+    if header_size < MIN_HEADER_SIZE || header_size > sector.len() {
+        return Err(From::from(format!("bad GPT header size {}", header_size)));
+    }
+    let header_crc = LittleEndian::read_u32(&sector[16..20]);
+    for b in &mut sector[16..20] {
+        *b = 0;
+    }
+    if crc32(&sector[..header_size]) != header_crc {
+        return Err(From::from(format!("GPT header at sector {} fails its CRC", lba)));
+    }
+
+    let header = Header {
+        entries_lba: LittleEndian::read_u64(&sector[72..80]),
+        entry_count: LittleEndian::read_u32(&sector[80..84]),
+        entry_size: LittleEndian::read_u32(&sector[84..88]) as usize,
+        entries_crc: LittleEndian::read_u32(&sector[88..92]),
+    };
+    if header.entry_size < MIN_ENTRY_SIZE || header.entry_size > MAX_ENTRY_SIZE
+        || header.entry_size % 8 != 0 || header.entry_count > MAX_ENTRIES
+    {
+        return Err(From::from(format!(
+            "bad GPT entry layout: {} entries of {} bytes",
+            header.entry_count, header.entry_size)));
+    }
+    Ok(header)
+}
+
+/// Read the partition entries described by `header`.
+fn read_entries<D: Read + Seek>(device: &mut D, sector_size: u64, header: &Header,
+                                disk_len: u64)
+    -> Result<Vec<Partition>, Box<error::Error>>
+{
+    let entries_len = header.entry_count as usize * header.entry_size;
+    let entries_start = lba_offset(header.entries_lba, sector_size)?;
+    match entries_start.checked_add(entries_len as u64) {
+        Some(entries_end) if entries_end <= disk_len => {},
+        _ => return Err(From::from("GPT partition entries lie outside the disk")),
+    }
+    let mut entries = vec![0; entries_len];
+    device.seek(SeekFrom::Start(entries_start))?;
+    device.read_exact(&mut entries)?;
+    if crc32(&entries) != header.entries_crc {
+        return Err(From::from("GPT partition entries fail their CRC"));
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks(header.entry_size).enumerate() {
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&entry[0..16]);
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first_lba = LittleEndian::read_u64(&entry[32..40]);
+        let last_lba = LittleEndian::read_u64(&entry[40..48]);
+        let attributes = LittleEndian::read_u64(&entry[48..56]);
+        let end = last_lba.checked_add(1)
+            .and_then(|end_lba| end_lba.checked_mul(sector_size));
+        let (start, end) = match end {
+            Some(end) if last_lba >= first_lba && end <= disk_len =>
+                (first_lba * sector_size, end),
+            _ => return Err(From::from(format!(
+                "GPT partition {} lies outside the disk", index + 1))),
+        };
+        let name = entry[56..128]
+            .chunks(2)
+            .map(LittleEndian::read_u16)
+            .take_while(|&c| c != 0)
+            .collect::<Vec<u16>>();
+
+        let mut partition = Partition::new(
+            index + 1, start, end - start,
+            PartitionType::Gpt(Guid(type_guid)),
+            attributes & LEGACY_BIOS_BOOTABLE != 0);
+        partition.name = String::from_utf16_lossy(&name);
+        partitions.push(partition);
+    }
+    Ok(partitions)
+}
+
+/// Byte offset of sector `lba`, which a corrupt table may put beyond any
+/// disk.
+fn lba_offset(lba: u64, sector_size: u64) -> Result<u64, Box<error::Error>> {
+    match lba.checked_mul(sector_size) {
+        Some(offset) => Ok(offset),
+        None => Err(From::from(format!("sector {} is out of range", lba))),
+    }
+}
+
+/// Parse the GUID Partition Table of `device`. The primary header is used
+/// if it is intact, otherwise the backup header at the end of the disk.
+/// Partitions are numbered by their entry, counting from 1.
+pub fn read_gpt<D: Read + Seek>(device: &mut D)
+    -> Result<Vec<Partition>, Box<error::Error>>
+{
+    let disk_len = device.seek(SeekFrom::End(0))?;
+    let mut first_error = None;
+    for &sector_size in SECTOR_SIZES.iter() {
+        let last_lba = match (disk_len / sector_size).checked_sub(1) {
+            Some(lba) if lba > 1 => lba,
+            _ => continue,
+        };
+        for &lba in [1, last_lba].iter() {
+            let result = read_header(device, sector_size, lba)
+                .and_then(|header| read_entries(device, sector_size, &header, disk_len));
+            match result {
+                Ok(partitions) => return Ok(partitions),
+                Err(err) => if first_error.is_none() {
+                    first_error = Some(err);
+                },
+            }
+        }
+    }
+    Err(first_error.unwrap_or(From::from("disk is too small for a GPT")))
+}
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path,PathBuf};
+
+use fat;
+use fat::{Dir,DirEntry,Image,RootEntry};
+
+/// What `Image::extract_tree` copied out of an image.
+#[derive(Debug,Default)]
+pub struct ExtractReport {
+    pub files: usize,
+    pub dirs: usize,
+    /// Files and directories that couldn't be copied, by path within the
+    /// image, with the reason. Nothing below a failed directory is copied.
+    pub failures: Vec<(String, Box<error::Error>)>,
+}
+
+#[test]
+fn test_add_tree() {
+    let src = ::std::env::temp_dir().join(format!("fatr-add-tree-{}", ::std::process::id()));
+    fs::create_dir_all(src.join("EFI/BOOT")).unwrap();
+    fs::create_dir_all(src.join("empty dir")).unwrap();
+    fs::write(src.join("EFI/BOOT/BOOTX64.EFI"), vec![0x4d; 5000]).unwrap();
+    fs::write(src.join("A long file name.txt"), b"hello").unwrap();
+
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir("DEST").unwrap();
+    let result = image.add_tree(&src, "DEST");
+    fs::remove_dir_all(&src).unwrap();
+    result.unwrap();
+
+    let entry = image.get_file_entry("DEST/EFI/BOOT/BOOTX64.EFI".to_string()).unwrap();
+    assert_eq!(image.read_file(&entry).unwrap(), vec![0x4d; 5000]);
+    assert!(entry.modified().is_some());
+    let entry = image.get_file_entry("dest/a long file name.txt".to_string()).unwrap();
+    assert_eq!(image.read_file(&entry).unwrap(), b"hello");
+    assert!(image.lookup("DEST/empty dir").unwrap().entry.is_subdir());
+    assert!(image.check().unwrap().problems.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_add_tree_symlink_loop() {
+    let src = ::std::env::temp_dir().join(format!("fatr-add-loop-{}", ::std::process::id()));
+    fs::create_dir_all(src.join("a/b")).unwrap();
+    ::std::os::unix::fs::symlink(&src, src.join("a/b/up")).unwrap();
+
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    let result = image.add_tree(&src, "");
+    fs::remove_dir_all(&src).unwrap();
+    assert!(result.unwrap_err().to_string().contains("links back"));
+}
+
+#[test]
+fn test_extract_tree() {
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir_all("EFI/BOOT").unwrap();
+    image.write_file("EFI/BOOT/BOOTX64.EFI".to_string(), &[0x4d; 5000]).unwrap();
+    image.write_file("A long file name.txt".to_string(), b"hello").unwrap();
+    image.write_file("BROKEN.BIN".to_string(), &[1; 1500]).unwrap();
+    let mut dir_entry = image.lookup("A long file name.txt").unwrap();
+    dir_entry.entry.set_is_read_only(true);
+    image.save_file_entry(&dir_entry).unwrap();
+    let broken = image.get_file_entry("BROKEN.BIN".to_string()).unwrap().entry_cluster();
+    image.set_fat_entry(broken, 0);
+
+    let dest = ::std::env::temp_dir().join(format!("fatr-extract-tree-{}", ::std::process::id()));
+    let result = image.extract_tree("", &dest);
+    let data = fs::read(dest.join("EFI/BOOT/BOOTX64.EFI"));
+    let metadata = fs::metadata(dest.join("A long file name.txt"));
+    let broken_exists = dest.join("BROKEN.BIN").exists();
+    let _ = fs::set_permissions(dest.join("A long file name.txt"),
+                                fs::metadata(&dest).unwrap().permissions());
+    fs::remove_dir_all(&dest).unwrap();
+
+    let report = result.unwrap();
+    assert_eq!((report.files, report.dirs), (2, 2));
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, "BROKEN.BIN");
+    assert_eq!(data.unwrap(), vec![0x4d; 5000]);
+    assert!(metadata.unwrap().permissions().readonly());
+    assert!(!broken_exists);
+}
+
+impl Image {
+    /// Copy the contents of the host directory `src` into the directory at
+    /// `path`, which must exist, recreating subdirectories. Entries keep
+    /// their long names, timestamps and read-only flags, unless a fixed
+    /// time is set in which case every entry gets that. Directory entries
+    /// are visited in name order so the result doesn't depend on the host.
+    /// Symbolic links are followed, except back into a directory being
+    /// copied, which is an error.
+    pub fn add_tree<P: AsRef<Path>>(&mut self, src: P, path: &str)
+        -> Result<(), Box<error::Error>>
+    {
+        let mut ancestors = vec![fs::canonicalize(src.as_ref())?];
+        self.add_host_dir(src.as_ref(), path, &mut ancestors)
+    }
+
+    /// Copy the host directory `src` into `path`. `ancestors` holds the
+    /// resolved host paths of `src` and the directories above it, so a link
+    /// back to one of them is refused rather than copied forever.
+    fn add_host_dir(&mut self, src: &Path, path: &str, ancestors: &mut Vec<PathBuf>)
+        -> Result<(), Box<error::Error>>
+    {
+        let mut host_entries = fs::read_dir(src)?.collect::<Result<Vec<_>, _>>()?;
+        host_entries.sort_by_key(|host_entry| host_entry.file_name());
+
+        for host_entry in host_entries {
+            let host_path = host_entry.path();
+            let name = match host_entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => return Err(From::from(format!(
+                    "{} is not a valid name", name.to_string_lossy()))),
+            };
+            let entry_path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+
+            // Follow symbolic links, copying what they point to.
+            let metadata = fs::metadata(&host_path)?;
+            if metadata.is_dir() {
+                let real_path = fs::canonicalize(&host_path)?;
+                if ancestors.contains(&real_path) {
+                    return Err(From::from(format!(
+                        "{} links back to {}, which contains it",
+                        host_path.display(), real_path.display())));
+                }
+                let mut dir_entry = self.create_dir(&entry_path)?;
+                self.set_host_metadata(&mut dir_entry.entry, &metadata);
+                // A read-only directory means something else to DOS.
+                dir_entry.entry.set_is_read_only(false);
+                self.save_file_entry(&dir_entry)?;
+                ancestors.push(real_path);
+                self.add_host_dir(&host_path, &entry_path, ancestors)?;
+                ancestors.pop();
+            } else if metadata.is_file() {
+                if metadata.len() > u32::max_value() as u64 {
+                    return Err(From::from(format!(
+                        "{} is too large for FAT", host_path.display())));
+                }
+                let mut file = fs::File::open(&host_path)?;
+                self.write_file_from(entry_path.clone(), &mut file, metadata.len() as u32)?;
+
+                let mut dir_entry = self.lookup(&entry_path)?;
+                self.set_host_metadata(&mut dir_entry.entry, &metadata);
+                self.save_file_entry(&dir_entry)?;
+            } else {
+                return Err(From::from(format!(
+                    "{} is not a file or directory", host_path.display())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Give `entry` the timestamps and read-only flag of a host file, or
+    /// the fixed time if there is one.
+    fn set_host_metadata(&self, entry: &mut RootEntry, metadata: &fs::Metadata) {
+        entry.copy_host_metadata(metadata);
+        if let Some(time) = self.fixed_time() {
+            entry.set_times(time);
+        }
+    }
+
+    /// Copy the directory at `path` and everything below it into the host
+    /// directory `dest`, creating it if needed. Files get back their last
+    /// write times and read-only flags. A file or directory that can't be
+    /// copied, because of a broken cluster chain or a name the host can't
+    /// use, is skipped and listed in the report rather than ending the
+    /// extraction.
+    pub fn extract_tree<P: AsRef<Path>>(&self, path: &str, dest: P)
+        -> Result<ExtractReport, Box<error::Error>>
+    {
+        let dir = self.lookup_dir(path)?;
+        fs::create_dir_all(dest.as_ref())?;
+
+        let mut report = ExtractReport::default();
+        // Directories already copied, by first cluster, so a directory
+        // that contains itself is only copied once. Zero is the root.
+        let mut visited = HashSet::new();
+        visited.insert(0);
+        if let Dir::Chain(cluster_num) = dir {
+            visited.insert(cluster_num);
+        }
+        let path = fat::split_path(path).join("/");
+        self.extract_dir(dir, &path, dest.as_ref(), &mut visited, &mut report)?;
+        Ok(report)
+    }
+
+    fn extract_dir(&self, dir: Dir, path: &str, dest: &Path,
+                   visited: &mut HashSet<u32>, report: &mut ExtractReport)
+        -> Result<(), Box<error::Error>>
+    {
+        for dir_entry in self.read_dir(dir)? {
+            let name = &dir_entry.name;
+            if dir_entry.entry.is_volume_label() || name == "." || name == ".." {
+                continue;
+            }
+            let entry_path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", path, name)
+            };
+
+            let result = if dir_entry.entry.is_subdir() {
+                self.extract_subdir(&dir_entry, &entry_path, dest, visited, report)
+            } else {
+                self.extract_file(&dir_entry, dest)
+                    .map(|()| report.files += 1)
+            };
+            if let Err(err) = result {
+                report.failures.push((entry_path, err));
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_subdir(&self, dir_entry: &DirEntry, path: &str, dest: &Path,
+                      visited: &mut HashSet<u32>, report: &mut ExtractReport)
+        -> Result<(), Box<error::Error>>
+    {
+        let host_path = dest.join(host_name(&dir_entry.name)?);
+        let entry = &dir_entry.entry;
+        if !visited.insert(entry.entry_cluster()) {
+            return Err(From::from(format!(
+                "directory at cluster {} contains itself", entry.entry_cluster())));
+        }
+        if !host_path.is_dir() {
+            fs::create_dir(&host_path)?;
+        }
+        report.dirs += 1;
+        self.extract_dir(self.entry_dir(entry), path, &host_path, visited, report)?;
+
+        // Copying the contents changed the directory's time, so set it last.
+        // Not every host can open a directory to do so, which is no reason
+        // to call the copy a failure.
+        if let Some(modified) = entry.modified() {
+            let _ = fs::File::open(&host_path)
+                .and_then(|dir| dir.set_modified(modified.to_system_time()));
+        }
+        Ok(())
+    }
+
+    fn extract_file(&self, dir_entry: &DirEntry, dest: &Path)
+        -> Result<(), Box<error::Error>>
+    {
+        let host_path = dest.join(host_name(&dir_entry.name)?);
+        let data = self.read_file(&dir_entry.entry)?;
+        let mut file = fs::File::create(&host_path)?;
+        file.write_all(&data)?;
+        dir_entry.entry.restore_host_metadata(&file)?;
+        Ok(())
+    }
+}
+
+/// Check that `name` can be used as a single component of a host path.
+fn host_name(name: &str) -> Result<&str, Box<error::Error>> {
+    if name.is_empty() || name == "." || name == ".."
+        || name.chars().any(|c| c == '/' || c == '\\' || c.is_control())
+    {
+        return Err(From::from(format!(
+            "\"{}\" is not a valid host file name", name.escape_default())));
+    }
+    Ok(name)
+}
@@ -1,3 +1,8 @@
+extern crate byteorder;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeSet;
 use std::error;
 use std::fs;
 use std::io;
@@ -6,107 +11,716 @@ use std::mem;
 use std::str;
 use std::path::Path;
 
+use self::byteorder::{LittleEndian,ByteOrder};
+
+use fat;
 use fat::RootEntry;
 use fat::BIOSParam;
+use fat::DateTime;
+use fat::FatType;
+use fat::{Dir,DirEntry};
+use fat::lfn;
+use fat::lfn::LongName;
+use fat::BlockDevice;
+use fat::device::{DeviceSlice,device_len};
+use fat::cache::{SectorCache,CACHE_SECTORS};
 
 // Always the same
 const BYTES_PER_ROOT_ENTRY: usize = 32;
 
+// Signatures marking a valid FAT32 FSInfo sector.
+const FS_INFO_LEAD_SIG: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIG: u32 = 0x61417272;
+const FS_INFO_TRAIL_SIG: u32 = 0xaa550000;
+
+fn entry_from_bytes(chunk: &[u8]) -> RootEntry {
+    let mut entry_bytes = [0; BYTES_PER_ROOT_ENTRY];
+    entry_bytes.clone_from_slice(chunk);
+
+    let entry: RootEntry;
+    unsafe { entry = mem::transmute(entry_bytes); }
+    entry
+}
+
+/// Open an image file for reading and, if permissions allow, writing.
+fn open_file(path: &Path) -> io::Result<fs::File> {
+    match fs::OpenOptions::new().read(true).write(true).open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied =>
+            fs::File::open(path),
+        result => result,
+    }
+}
+
 #[test]
 fn test_root_entry_size() {
     assert_eq!(mem::size_of::<RootEntry>(), BYTES_PER_ROOT_ENTRY);
 }
 
+#[test]
+fn test_allocate_clusters() {
+    let mut bpb = BIOSParam::new();
+    bpb.bytes_per_sector = 512;
+    bpb.sectors_per_cluster = 1;
+    bpb.reserved_sectors = 1;
+    bpb.max_roots = 16;
+    bpb.sectors_per_fat = 1;
+    bpb.sectors = 64;
+    let mut image = Image::new(bpb, 64 * 512);
+
+    let chain = image.allocate_clusters(3).unwrap();
+    assert_eq!(chain, vec![2, 3, 4]);
+    assert_eq!(image.get_fat_entry(2), 3);
+    assert_eq!(image.get_fat_entry(3), 4);
+    assert_eq!(image.get_fat_entry(4), 0xfff);
+    assert_eq!(image.get_free_fat_entry(), Some(5));
+    assert!(image.allocate_clusters(1000).is_err());
+
+    // Clusters past the data area can't be read or written.
+    let past_end = image.cluster_count() as u32 + 2;
+    assert!(image.write_cluster(past_end - 1, b"last").is_ok());
+    assert!(image.write_cluster(past_end, b"past").is_err());
+    assert!(image.read_cluster(past_end).is_err());
+}
+
+#[test]
+fn test_set_fat_entry() {
+    let mut bpb = BIOSParam::new();
+    bpb.bytes_per_sector = 512;
+    bpb.sectors_per_cluster = 1;
+    bpb.reserved_sectors = 1;
+    bpb.fat_count = 3;
+    bpb.max_roots = 16;
+    bpb.sectors_per_fat = 1;
+    bpb.sectors = 64;
+    let mut image = Image::new(bpb, 64 * 512);
+
+    image.set_fat_entry(4, 0xabc);
+    image.set_fat_entry(5, 0x123);
+    assert_eq!(image.get_fat_entry(4), 0xabc);
+    assert_eq!(image.get_fat_entry(5), 0x123);
+    assert_eq!(&image.fat_copy(0).unwrap()[6..9], &[0xbc, 0x3a, 0x12]);
+
+    // Rewriting one half of a byte pair leaves its neighbour intact.
+    image.set_fat_entry(4, 0xfff);
+    assert_eq!(image.get_fat_entry(5), 0x123);
+    image.flush().unwrap();
+    let fat = image.fat_copy(0).unwrap();
+    assert!((1 .. 3).all(|i| image.fat_copy(i).unwrap() == fat));
+}
+
+#[test]
+fn test_fat16_entries() {
+    let mut bpb = BIOSParam::new();
+    bpb.bytes_per_sector = 512;
+    bpb.sectors_per_cluster = 1;
+    bpb.reserved_sectors = 1;
+    bpb.max_roots = 512;
+    bpb.sectors_per_fat = 20;
+    bpb.sectors = 5000;
+    let mut image = Image::new(bpb, 5000 * 512);
+    assert_eq!(image.fat_type(), FatType::Fat16);
+
+    let chain = image.allocate_clusters(2).unwrap();
+    assert_eq!(chain, vec![2, 3]);
+    assert_eq!(&image.fat_copy(1).unwrap()[4..8], &[0x03, 0x00, 0xff, 0xff]);
+    assert_eq!(image.cluster_chain(2), vec![2, 3]);
+}
+
+#[test]
+fn test_read_dir_with_deleted() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.write_file("KEEP.TXT".to_string(), b"keep").unwrap();
+    image.write_file("A long name.txt".to_string(), b"gone").unwrap();
+    image.remove_file("A long name.txt".to_string()).unwrap();
+
+    let root = image.root_dir_location();
+    let names = image.read_dir(root).unwrap().into_iter()
+        .map(|dir_entry| dir_entry.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["KEEP.TXT"]);
+    let dir_entries = image.read_dir_with_deleted(root).unwrap();
+    assert_eq!(dir_entries.len(), 2);
+    assert_eq!(dir_entries[1].name, "?LONGN~1.TXT");
+    assert!(dir_entries[1].entry.is_deleted());
+    assert_eq!(dir_entries[0].entry.attribute_flags(), "-----A");
+}
+
+#[test]
+fn test_image_from_device() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.write_file("HELLO.TXT".to_string(), b"hello").unwrap();
+
+    let mut buffer = io::Cursor::new(vec![0xaa; 512]);
+    buffer.seek(SeekFrom::End(0)).unwrap();
+    image.save_to(&mut buffer).unwrap();
+
+    let slice = DeviceSlice::new(buffer, 512, 1440 * 1024);
+    let image = Image::from_device(slice).unwrap();
+    let entry = image.get_file_entry("HELLO.TXT".to_string()).unwrap();
+    assert_eq!(image.read_file(&entry).unwrap(), b"hello");
+
+    assert!(Image::from_device(io::Cursor::new(vec![0; 512])).is_err());
+}
+
+#[test]
+fn test_read_file() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    let cluster_size = image.cluster_size();
+    let data = (0 .. 3 * cluster_size + 100).map(|i| i as u8).collect::<Vec<u8>>();
+    image.write_file("DATA.BIN".to_string(), &data).unwrap();
+
+    let entry = image.get_file_entry("DATA.BIN".to_string()).unwrap();
+    let chain = image.cluster_chain(entry.entry_cluster());
+    assert_eq!(chain.len(), 4);
+    // Whatever follows the file in its last cluster is not part of it.
+    let mut last = data[3 * cluster_size ..].to_vec();
+    last.resize(cluster_size, 0xee);
+    image.write_cluster(chain[3], &last).unwrap();
+    assert_eq!(image.read_file(&entry).unwrap(), data);
+}
+
+#[test]
+fn test_write_file_from() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    let cluster_size = image.cluster_size();
+    let data = (0 .. 2 * cluster_size + 7).map(|i| i as u8).collect::<Vec<u8>>();
+
+    // Only `size` bytes are taken from the reader.
+    let mut reader = io::Cursor::new(&data);
+    let size = (2 * cluster_size) as u32;
+    let entry = image.write_file_from("PART.BIN".to_string(), &mut reader, size).unwrap();
+    assert_eq!(entry.file_size, size);
+    assert_eq!(image.read_file(&entry).unwrap(), &data[.. 2 * cluster_size]);
+    assert_eq!(reader.position(), 2 * cluster_size as u64);
+
+    let entry = image.write_file_from("EMPTY.BIN".to_string(), &mut io::empty(), 0).unwrap();
+    assert_eq!(entry.entry_cluster(), 0);
+
+    // A reader that ends early is an error.
+    let mut reader = io::Cursor::new(&data);
+    assert!(image.write_file_from("SHORT.BIN".to_string(), &mut reader,
+                                  data.len() as u32 + 1).is_err());
+}
+
+#[test]
+fn test_remove_file() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.write_file("A long file name.txt".to_string(), &[7; 2000]).unwrap();
+    image.flush().unwrap();
+    let dir_entry = image.lookup("A long file name.txt").unwrap();
+    let chain = image.cluster_chain(dir_entry.entry.entry_cluster());
+    assert_eq!(chain.len(), 4);
+    assert!(dir_entry.lfn_start < dir_entry.index);
+
+    image.remove_file("A long file name.txt".to_string()).unwrap();
+    image.flush().unwrap();
+    for index in 0 .. image.bios_parameter().fat_count as usize {
+        let fat = image.fat_copy(index).unwrap();
+        for &cluster_num in chain.iter() {
+            assert_eq!(image.fat_type().read_entry(&fat, cluster_num), 0);
+        }
+    }
+    // The short entry and every long filename slot before it are freed.
+    let bytes = image.dir_bytes(dir_entry.dir).unwrap();
+    for index in dir_entry.lfn_start ..= dir_entry.index {
+        assert_eq!(bytes[index as usize * BYTES_PER_ROOT_ENTRY], 0xe5);
+    }
+    assert!(image.lookup("A long file name.txt").is_err());
+}
+
+#[test]
+fn test_create_dir() {
+    let mut image = fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    let dot_entries = |image: &Image, cluster_num: u32| {
+        let dir_entries = image.read_dir(Dir::Chain(cluster_num)).unwrap();
+        assert_eq!(dir_entries[0].name, ".");
+        assert_eq!(dir_entries[1].name, "..");
+        assert!(dir_entries[0].entry.is_subdir() && dir_entries[1].entry.is_subdir());
+        (dir_entries[0].entry.entry_cluster(), dir_entries[1].entry.entry_cluster())
+    };
+
+    let a = image.create_dir("A").unwrap().entry.entry_cluster();
+    assert_eq!(dot_entries(&image, a), (a, 0));
+    assert!(image.create_dir("A").is_err());
+    assert!(image.create_dir("X/Y").is_err());
+
+    // Missing parents are created as with `mkdir -p`.
+    image.create_dir_all("A/B/C").unwrap();
+    let b = image.lookup("A/B").unwrap().entry.entry_cluster();
+    let c = image.lookup("A/B/C").unwrap().entry.entry_cluster();
+    assert_eq!(dot_entries(&image, b), (b, a));
+    assert_eq!(dot_entries(&image, c), (c, b));
+    assert_eq!(image.create_dir_all("A/B").unwrap(), Dir::Chain(b));
+}
+
+#[test]
+fn test_fat32_image() {
+    let mut image = fat::ImageBuilder::new(64 * 1024 * 1024)
+        .fat_type(FatType::Fat32).build().unwrap();
+    let root_cluster = image.bios_parameter().root_cluster;
+    // More files than the first root directory cluster holds, so the root
+    // directory chain has to grow.
+    let count = image.cluster_size() / BYTES_PER_ROOT_ENTRY + 8;
+    for n in 0 .. count {
+        image.write_file(format!("FILE{}.TXT", n), format!("file {}", n).as_bytes())
+            .unwrap();
+    }
+    let root_clusters = image.cluster_chain(root_cluster).len();
+    assert_eq!(root_clusters, 2);
+
+    let mut saved = Vec::new();
+    image.save_to(&mut saved).unwrap();
+    let image = Image::from_device(io::Cursor::new(saved.clone())).unwrap();
+    assert_eq!(image.fat_type(), FatType::Fat32);
+    assert_eq!(image.root_dir_location(), Dir::Chain(root_cluster));
+    let names = image.read_dir(image.root_dir_location()).unwrap()
+        .into_iter()
+        .filter(|dir_entry| !dir_entry.entry.is_volume_label())
+        .map(|dir_entry| dir_entry.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names.len(), count);
+    for n in 0 .. count {
+        assert_eq!(names[n], format!("FILE{}.TXT", n));
+        let entry = image.get_file_entry(names[n].clone()).unwrap();
+        assert_eq!(image.read_file(&entry).unwrap(), format!("file {}", n).as_bytes());
+    }
+
+    // FSInfo counts every cluster the files and root directory don't use.
+    let bpb = image.bios_parameter();
+    let fs_info = &saved[bpb.fs_info_sector as usize * image.sector_size() ..][.. 512];
+    let free_count = LittleEndian::read_u32(&fs_info[488..492]) as usize;
+    assert_eq!(free_count, image.cluster_count() - count - root_clusters);
+    let next_free = LittleEndian::read_u32(&fs_info[492..496]) as usize;
+    assert_eq!(Some(next_free), image.get_free_fat_entry());
+}
+
+/// In-memory device shared with the test, recording where it is written.
+#[cfg(test)]
+struct SharedDevice {
+    data: ::std::rc::Rc<RefCell<Vec<u8>>>,
+    writes: ::std::rc::Rc<RefCell<Vec<u64>>>,
+    position: u64,
+}
+
+#[cfg(test)]
+impl io::Read for SharedDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.borrow();
+        let mut cursor = io::Cursor::new(&data[..]);
+        cursor.set_position(self.position);
+        let read = io::Read::read(&mut cursor, buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.borrow_mut().push(self.position);
+        let mut data = self.data.borrow_mut();
+        let mut cursor = io::Cursor::new(&mut *data);
+        cursor.set_position(self.position);
+        let written = cursor.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Seek for SharedDevice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.borrow().len() as i64;
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (len + offset) as u64,
+            SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+        };
+        Ok(self.position)
+    }
+}
+
+#[test]
+fn test_flush_dirty_sectors() {
+    let mut formatted = Vec::new();
+    fat::ImageBuilder::new(1440 * 1024).build().unwrap()
+        .save_to(&mut formatted).unwrap();
+
+    let data = ::std::rc::Rc::new(RefCell::new(formatted.clone()));
+    let writes = ::std::rc::Rc::new(RefCell::new(Vec::new()));
+    let mut image = Image::from_device(SharedDevice {
+        data: data.clone(),
+        writes: writes.clone(),
+        position: 0,
+    }).unwrap();
+    image.write_file("HELLO.TXT".to_string(), b"hello").unwrap();
+
+    // Nothing reaches the device before the flush.
+    assert!(writes.borrow().is_empty());
+    assert!(*data.borrow() == formatted);
+
+    // Then only the first sector of both FATs, the root directory sector
+    // and the file's cluster are written.
+    image.flush().unwrap();
+    let mut written = writes.borrow().clone();
+    written.sort();
+    let bpb = image.bios_parameter();
+    let fat_sectors = bpb.sectors_per_fat as u64;
+    assert_eq!(written, vec![512, (1 + fat_sectors) * 512, (1 + 2 * fat_sectors) * 512,
+                             bpb.data_start_sector() as u64 * 512]);
+
+    let image = Image::from_device(io::Cursor::new(data.borrow().clone())).unwrap();
+    let entry = image.get_file_entry("HELLO.TXT".to_string()).unwrap();
+    assert_eq!(image.read_file(&entry).unwrap(), b"hello");
+}
+
+/// A FAT filesystem on a device. Only the reserved sectors and the active
+/// FAT are held in memory; directories and file data are read and written
+/// through a sector cache. The FAT and boot sector only reach the device on
+/// `flush`; other changes may be written earlier, when the cache is full.
 #[derive(Debug)]
 pub struct Image {
+    cache: RefCell<SectorCache>,
+    length: u64,
     boot_sector: Vec<u8>,
-    fat_1: Vec<u8>,
-    fat_2: Vec<u8>,
-    root_dir: Vec<u8>,
-    data_area: Vec<u8>,
+    boot_dirty: BTreeSet<usize>,
+    fat: Vec<u8>,
+    fat_dirty: BTreeSet<usize>,
     bpb_data: BIOSParam,
+    fat_type: FatType,
+    fixed_time: Option<DateTime>,
 }
 
 #[allow(dead_code)]
 impl Image {
-    /// Create a new blank FAT Image from a defined BPB
+    /// Create a new blank FAT Image from a defined BPB, held in memory.
     fn new(bpb: BIOSParam, length: usize) -> Image {
-        let boot_sector_size = bpb.bytes_per_sector as usize
-            * bpb.reserved_sectors as usize;
-        let bytes_per_fat = (bpb.sectors_per_fat
-            * bpb.bytes_per_sector as u32) as usize;
-        let bytes_of_roots =
-            (bpb.max_roots as usize * BYTES_PER_ROOT_ENTRY) as usize;
-        let data_offset = boot_sector_size as usize
-            + (bytes_per_fat * 2) as usize + bytes_of_roots as usize;
-        let bytes_per_data_area = length - data_offset;
+        let device = io::Cursor::new(vec![0; length]);
+        Image::with_device(Box::new(device), bpb, length as u64)
+    }
+
+    /// Create a FAT Image on `device` with the reserved sectors and FAT
+    /// zeroed in memory.
+    fn with_device(device: Box<BlockDevice>, bpb: BIOSParam, length: u64) -> Image {
+        let sector_size = bpb.bytes_per_sector as usize;
+        let boot_sector_size = sector_size * bpb.reserved_sectors as usize;
+        let bytes_per_fat = bpb.sectors_per_fat as usize * sector_size;
         Image {
+            cache: RefCell::new(SectorCache::new(device, sector_size, CACHE_SECTORS)),
+            length: length,
             boot_sector: vec![0; boot_sector_size],
-            fat_1: vec![0; bytes_per_fat],
-            fat_2: vec![0; bytes_per_fat],
-            root_dir: vec![0; bytes_of_roots],
-            data_area: vec![0; bytes_per_data_area],
+            boot_dirty: BTreeSet::new(),
+            fat: vec![0; bytes_per_fat],
+            fat_dirty: BTreeSet::new(),
+            fat_type: bpb.fat_type(),
             bpb_data: bpb,
+            fixed_time: None,
         }
     }
 
-    /// Create a new FAT Image from the specified file.
-    pub fn from_file<P: AsRef<Path>>(image_fn: P)
+    /// Format `device` as a FAT filesystem. The reserved sectors start with
+    /// the boot sector for `bpb`, each FAT gets its two reserved entries and
+    /// the root directory is empty apart from the volume label, if there is
+    /// one. Entries get `fixed_time` as their timestamps if it is set.
+    /// Nothing is written to the device until `flush`.
+    pub(crate) fn format(mut device: Box<BlockDevice>, bpb: BIOSParam,
+                         label: Option<[u8; 11]>, fixed_time: Option<DateTime>)
         -> Result<Image, Box<error::Error>>
     {
-        let metadata = fs::metadata(image_fn.as_ref())?;
-        let bpb = BIOSParam::from_file(image_fn.as_ref(), 0)?;
+        let length = bpb.len() as u64;
+        if (bpb.data_start_sector() * bpb.bytes_per_sector as usize) as u64 > length {
+            return Err(From::from("image is too small to format"));
+        }
+        // Make sure the device can hold the whole filesystem, growing it if
+        // it is a file.
+        if device_len(&mut device)? < length {
+            device.seek(SeekFrom::Start(length - 1))?;
+            device.write_all(&[0])?;
+        }
 
-        let mut file = fs::File::open(image_fn.as_ref())?;
-        let mut image = Image::new(bpb, metadata.len() as usize);
+        let boot_sector = bpb.to_bytes();
+        let mut image = Image::with_device(device, bpb, length);
+        image.fixed_time = fixed_time;
+        image.boot_sector[..boot_sector.len()].copy_from_slice(&boot_sector);
+        let (boot_len, fat_len) = (image.boot_sector.len(), image.fat.len());
+        image.mark_boot_dirty(0, boot_len);
+        image.mark_fat_dirty(0, fat_len);
 
-        try!(file.read_exact(&mut image.boot_sector));
-        try!(file.read_exact(&mut image.fat_1));
-        try!(file.read_exact(&mut image.fat_2));
-        try!(file.read_exact(&mut image.root_dir));
-        try!(file.read_exact(&mut image.data_area));
+        let root_dir = image.root_dir_offset();
+        let root_dir_len = image.data_offset() - root_dir;
+        image.cache.borrow_mut().write(root_dir, &vec![0; root_dir_len as usize])?;
 
+        let end_of_chain = image.fat_type.end_of_chain();
+        let media_id = image.bpb_data.media_id as u32;
+        image.set_fat_entry(0, 0x0fffff00 | media_id);
+        image.set_fat_entry(1, end_of_chain);
+
+        if image.fat_type == FatType::Fat32 {
+            let root_cluster = image.bpb_data.root_cluster;
+            image.set_fat_entry(root_cluster, end_of_chain);
+            image.write_cluster(root_cluster, &[])?;
+
+            let sector_size = image.sector_size();
+            let start = image.bpb_data.fs_info_sector as usize * sector_size;
+            {
+                let fs_info = &mut image.boot_sector[start .. start + 512];
+                LittleEndian::write_u32(&mut fs_info[0..4], FS_INFO_LEAD_SIG);
+                LittleEndian::write_u32(&mut fs_info[484..488], FS_INFO_STRUCT_SIG);
+                LittleEndian::write_u32(&mut fs_info[508..512], FS_INFO_TRAIL_SIG);
+            }
+            image.update_fs_info();
+
+            // The backup boot sector is followed by a backup of FSInfo.
+            let backup = image.bpb_data.backup_boot_sector as usize * sector_size;
+            if backup != 0 && backup + 2 * sector_size <= image.boot_sector.len() {
+                let copy = image.boot_sector[.. 2 * sector_size].to_vec();
+                image.boot_sector[backup .. backup + 2 * sector_size]
+                    .copy_from_slice(&copy);
+                image.mark_boot_dirty(backup, 2 * sector_size);
+            }
+        }
+
+        if let Some(label) = label {
+            let mut entry = RootEntry::new();
+            entry.filename.copy_from_slice(&label[..8]);
+            entry.extension.copy_from_slice(&label[8..]);
+            entry.set_is_volume_label(true);
+            entry.set_modified(image.now());
+            let dir_entry = DirEntry {
+                name: lfn::short_name_string(&label),
+                dir: image.root_dir_location(),
+                index: 0,
+                lfn_start: 0,
+                entry: entry,
+            };
+            image.save_file_entry(&dir_entry)?;
+        }
         Ok(image)
     }
 
+    /// Create a new FAT Image from the specified file. The file is opened
+    /// for writing too if permissions allow, so changes can be flushed.
+    pub fn from_file<P: AsRef<Path>>(image_fn: P)
+        -> Result<Image, Box<error::Error>>
+    {
+        Image::from_device(open_file(image_fn.as_ref())?)
+    }
+
     /// Create a new FAT Image from the specified file and offset.
     pub fn from_file_offset<P: AsRef<Path>>(image_fn: P, start: usize, length: usize)
         -> Result<Image, Box<error::Error>>
     {
-        let metadata = fs::metadata(image_fn.as_ref())?;
-        let bpb = BIOSParam::from_file(image_fn.as_ref(), start)?;
-
-        if metadata.is_file() && (start + length > (metadata.len() as usize)) {
+        let mut file = open_file(image_fn.as_ref())?;
+        if start as u64 + length as u64 > device_len(&mut file)? {
             return Err(From::from(format!("start + offset outside image bounds")));
         }
+        Image::from_device(DeviceSlice::new(file, start as u64, length as u64))
+    }
 
-        let mut file = fs::File::open(image_fn.as_ref())?;
-        file.seek(SeekFrom::Start(start as u64))?;
+    /// Create a new FAT Image from partition `number` of a disk image file.
+    pub fn from_file_partition<P: AsRef<Path>>(image_fn: P, number: usize)
+        -> Result<Image, Box<error::Error>>
+    {
+        let mut file = open_file(image_fn.as_ref())?;
+        let partition = fat::find_partition(&mut file, number)?;
+        // Partition types are often wrong, so only blame the type once the
+        // contents turn out not to be FAT either.
+        let not_fat = if partition.is_fat() {
+            None
+        } else {
+            Some(partition.partition_type.to_string())
+        };
+        Image::from_device(partition.slice(file)).map_err(|err| match not_fat {
+            Some(partition_type) => From::from(format!(
+                "partition {} is a {} partition, not FAT: {}",
+                number, partition_type, err)),
+            None => err,
+        })
+    }
 
-        let mut image = Image::new(bpb, length);
+    /// Create a new FAT Image from the EFI system partition of a disk image
+    /// file.
+    pub fn from_file_esp<P: AsRef<Path>>(image_fn: P)
+        -> Result<Image, Box<error::Error>>
+    {
+        let mut file = open_file(image_fn.as_ref())?;
+        let partition = fat::find_esp(&mut file)?;
+        Image::from_device(partition.slice(file))
+    }
 
-        try!(file.read_exact(&mut image.boot_sector));
-        try!(file.read_exact(&mut image.fat_1));
-        try!(file.read_exact(&mut image.fat_2));
-        try!(file.read_exact(&mut image.root_dir));
-        try!(file.read_exact(&mut image.data_area));
+    /// Create a new FAT Image from any storage that can read, write and
+    /// seek, such as a `File` or a `Cursor` over an in-memory buffer. The
+    /// filesystem fills the whole device; wrap it in a `DeviceSlice` to use
+    /// a filesystem stored at an offset. Only the reserved sectors and the
+    /// active FAT are read up front.
+    pub fn from_device<D: BlockDevice + 'static>(mut device: D)
+        -> Result<Image, Box<error::Error>>
+    {
+        let length = device_len(&mut device)?;
+        let bpb = BIOSParam::read_from(&mut device)?;
+        if !bpb.is_valid() {
+            return Err(From::from("not a FAT filesystem"));
+        }
+        if (bpb.data_start_sector() * bpb.bytes_per_sector as usize) as u64 > length {
+            return Err(From::from("image is smaller than its filesystem"));
+        }
 
+        let mut image = Image::with_device(Box::new(device), bpb, length);
+        let active_fat = image.bpb_data.active_fat();
+        let fat_offset = image.fat_offset(active_fat);
+        {
+            let mut cache = image.cache.borrow_mut();
+            cache.read_raw(0, &mut image.boot_sector)?;
+            cache.read_raw(fat_offset, &mut image.fat)?;
+        }
         Ok(image)
     }
 
-    /// Save the FAT filesystem image to the specified file.
+    /// Write every change made since the image was opened back to its
+    /// device. Only the sectors that changed are written.
+    pub fn flush(&mut self) -> Result<(), Box<error::Error>> {
+        let writes = self.pending_writes();
+        let mut cache = self.cache.borrow_mut();
+        for &(offset, data) in writes.iter() {
+            cache.write_raw(offset, data)?;
+        }
+        cache.flush()?;
+        drop(cache);
+
+        self.boot_dirty.clear();
+        self.fat_dirty.clear();
+        Ok(())
+    }
+
+    /// Save a copy of the FAT filesystem image, including any changes that
+    /// haven't been flushed, to the specified file. This must not be the
+    /// file the image was read from; use `flush` to update that in place.
     pub fn save<P: AsRef<Path>>(&self, image_fn: P)
         -> Result<(), io::Error>
     {
-        let mut file = fs::File::create(image_fn.as_ref())?;
+        self.save_to(fs::File::create(image_fn.as_ref())?)
+    }
 
-        try!(file.write_all(&self.boot_sector));
-        try!(file.write_all(&self.fat_1));
-        try!(file.write_all(&self.fat_2));
-        try!(file.write_all(&self.root_dir));
-        try!(file.write_all(&self.data_area));
+    /// Write a copy of the whole FAT filesystem image, including any changes
+    /// that haven't been flushed, to `device` starting at its current
+    /// position. The image's own device is left untouched.
+    pub fn save_to<D: Write>(&self, mut device: D)
+        -> Result<(), io::Error>
+    {
+        const CHUNK_SIZE: u64 = 1024 * 1024;
+        let writes = self.pending_writes();
+        let mut cache = self.cache.borrow_mut();
+        let mut offset = 0;
+        while offset < self.length {
+            let len = cmp::min(CHUNK_SIZE, self.length - offset);
+            let mut chunk = vec![0; len as usize];
+            cache.read_raw(offset, &mut chunk)?;
+            for &(start, data) in writes.iter() {
+                let end = start + data.len() as u64;
+                if end <= offset || start >= offset + len {
+                    continue;
+                }
+                let from = cmp::max(start, offset);
+                let to = cmp::min(end, offset + len);
+                chunk[(from - offset) as usize .. (to - offset) as usize]
+                    .copy_from_slice(&data[(from - start) as usize .. (to - start) as usize]);
+            }
+            device.write_all(&chunk)?;
+            offset += len;
+        }
+        device.flush()
+    }
 
-        Ok(())
+    /// Changed sectors of the reserved area and FATs that still need to be
+    /// written, as (device offset, data) pairs. Changes to the active FAT
+    /// go to every copy unless FAT32 mirroring is disabled.
+    fn pending_writes(&self) -> Vec<(u64, &[u8])> {
+        let sector_size = self.sector_size();
+        let mut writes = Vec::new();
+        for &sector in self.boot_dirty.iter() {
+            let start = sector * sector_size;
+            writes.push((start as u64, &self.boot_sector[start .. start + sector_size]));
+        }
+
+        let mirroring = self.bpb_data.fat_mirroring();
+        let active_fat = self.bpb_data.active_fat();
+        for fat_index in 0 .. self.bpb_data.fat_count as usize {
+            if !mirroring && fat_index != active_fat {
+                continue;
+            }
+            let fat_offset = self.fat_offset(fat_index);
+            for &sector in self.fat_dirty.iter() {
+                let start = sector * sector_size;
+                writes.push((fat_offset + start as u64,
+                    &self.fat[start .. start + sector_size]));
+            }
+        }
+        writes
+    }
+
+    /// Raw contents of FAT copy `index` as they would be after a flush.
+    pub fn fat_copy(&self, index: usize) -> Result<Vec<u8>, Box<error::Error>> {
+        if index >= self.bpb_data.fat_count as usize {
+            return Err(From::from(format!("image has no FAT {}", index)));
+        }
+        let mut fat = vec![0; self.fat.len()];
+        self.cache.borrow_mut().read_raw(self.fat_offset(index), &mut fat)?;
+        if self.bpb_data.fat_mirroring() || index == self.bpb_data.active_fat() {
+            let sector_size = self.sector_size();
+            for &sector in self.fat_dirty.iter() {
+                let start = sector * sector_size;
+                fat[start .. start + sector_size]
+                    .copy_from_slice(&self.fat[start .. start + sector_size]);
+            }
+        }
+        Ok(fat)
+    }
+
+    /// Rewrite every copy of the FAT from the active FAT on the next flush,
+    /// rather than only the sectors that changed.
+    pub fn sync_fats(&mut self) {
+        let fat_len = self.fat.len();
+        self.mark_fat_dirty(0, fat_len);
+    }
+
+    fn mark_boot_dirty(&mut self, start: usize, len: usize) {
+        let sector_size = self.sector_size();
+        for sector in start / sector_size .. (start + len + sector_size - 1) / sector_size {
+            self.boot_dirty.insert(sector);
+        }
+    }
+
+    fn mark_fat_dirty(&mut self, start: usize, len: usize) {
+        let sector_size = self.sector_size();
+        for sector in start / sector_size .. (start + len + sector_size - 1) / sector_size {
+            self.fat_dirty.insert(sector);
+        }
+    }
+
+    /// Device offset of FAT copy `index`.
+    fn fat_offset(&self, index: usize) -> u64 {
+        let bpb = &self.bpb_data;
+        (bpb.reserved_sectors as u64 + index as u64 * bpb.sectors_per_fat as u64)
+            * bpb.bytes_per_sector as u64
+    }
+
+    /// Device offset of the FAT12/16 root directory.
+    fn root_dir_offset(&self) -> u64 {
+        self.fat_offset(self.bpb_data.fat_count as usize)
+    }
+
+    /// Device offset of the first data cluster.
+    fn data_offset(&self) -> u64 {
+        self.bpb_data.data_start_sector() as u64 * self.sector_size() as u64
+    }
+
+    /// Device offset of data cluster `cluster_num`.
+    fn cluster_offset(&self, cluster_num: u32) -> u64 {
+        self.data_offset() + (cluster_num as u64 - 2) * self.cluster_size() as u64
     }
 
     /// Extract the BIOS Parameter Block (BPB) from the FAT filesystem.
@@ -114,6 +728,66 @@ impl Image {
         self.bpb_data.clone()
     }
 
+    /// Replace the BIOS Parameter Block, rewriting the boot sector and the
+    /// FAT32 backup boot sector. Fields that describe the filesystem layout
+    /// can't be changed on an existing image.
+    pub fn set_bios_parameter(&mut self, bpb: BIOSParam)
+        -> Result<(), Box<error::Error>>
+    {
+        let old = &self.bpb_data;
+        if bpb.bytes_per_sector != old.bytes_per_sector
+            || bpb.sectors_per_cluster != old.sectors_per_cluster
+            || bpb.reserved_sectors != old.reserved_sectors
+            || bpb.fat_count != old.fat_count
+            || bpb.max_roots != old.max_roots
+            || bpb.sectors != old.sectors
+            || bpb.sectors_per_fat != old.sectors_per_fat
+            || bpb.root_cluster != old.root_cluster
+            || bpb.fs_info_sector != old.fs_info_sector
+            || bpb.backup_boot_sector != old.backup_boot_sector
+        {
+            return Err(From::from("can't change the layout of the filesystem"));
+        }
+
+        let boot_sector = bpb.to_bytes();
+        self.boot_sector[..boot_sector.len()].copy_from_slice(&boot_sector);
+        self.mark_boot_dirty(0, boot_sector.len());
+        if self.fat_type == FatType::Fat32 {
+            let backup = bpb.backup_boot_sector as usize * self.sector_size();
+            if backup != 0 && backup + boot_sector.len() <= self.boot_sector.len() {
+                self.boot_sector[backup .. backup + boot_sector.len()]
+                    .copy_from_slice(&boot_sector);
+                self.mark_boot_dirty(backup, boot_sector.len());
+            }
+        }
+        self.bpb_data = bpb;
+        Ok(())
+    }
+
+    /// Give every entry created from now on the timestamp `time` rather
+    /// than the current time, for reproducible images. Clusters and entry
+    /// slots are always allocated lowest first, so the same changes made
+    /// with a fixed time give identical images.
+    pub fn set_fixed_time(&mut self, time: Option<DateTime>) {
+        self.fixed_time = time;
+    }
+
+    /// The time set with `set_fixed_time`, if any.
+    pub fn fixed_time(&self) -> Option<DateTime> {
+        self.fixed_time
+    }
+
+    /// Timestamp for entries created now: the fixed time if there is one,
+    /// the current time otherwise.
+    pub fn now(&self) -> DateTime {
+        self.fixed_time.unwrap_or_else(DateTime::now)
+    }
+
+    /// FAT type (FAT12, FAT16 or FAT32) of the filesystem.
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
     /// FAT sector size in bytes.
     pub fn sector_size(&self) -> usize {
         self.bpb_data.bytes_per_sector as usize
@@ -121,7 +795,7 @@ impl Image {
 
     /// FAT volume label
     pub fn volume_label(&self) -> Result<String, Box<error::Error>> {
-        let entries = self.root_entries();
+        let entries = self.root_entries()?;
         for entry in entries {
             if !entry.is_volume_label() {
                 continue;
@@ -134,145 +808,708 @@ impl Image {
         return Ok("has no label".to_string());
     }
 
+    /// Location of the root directory. FAT32 stores the root directory in
+    /// a cluster chain like any other directory.
+    pub fn root_dir_location(&self) -> Dir {
+        match self.fat_type {
+            FatType::Fat32 => Dir::Chain(self.bpb_data.root_cluster),
+            _ => Dir::Root,
+        }
+    }
+
+    /// Raw bytes of every entry slot in a directory.
+    fn dir_bytes(&self, dir: Dir) -> Result<Vec<u8>, Box<error::Error>> {
+        match dir {
+            Dir::Root => {
+                let mut bytes = vec![0;
+                    self.bpb_data.max_roots as usize * BYTES_PER_ROOT_ENTRY];
+                self.cache.borrow_mut().read(self.root_dir_offset(), &mut bytes)?;
+                Ok(bytes)
+            },
+            Dir::Chain(first_cluster) => {
+                let mut bytes = Vec::new();
+                for cluster_num in self.cluster_chain(first_cluster) {
+                    bytes.extend(self.read_cluster(cluster_num)?);
+                }
+                Ok(bytes)
+            },
+        }
+    }
+
+    /// Write the bytes of one entry slot in a directory. Directories stored
+    /// in a cluster chain grow by a zeroed cluster when `index` is past
+    /// their end.
+    fn write_dir_slot(&mut self, dir: Dir, index: u16, bytes: &[u8])
+        -> Result<(), Box<error::Error>>
+    {
+        let offset = index as usize * BYTES_PER_ROOT_ENTRY;
+        match dir {
+            Dir::Root => {
+                let root_dir_len = self.bpb_data.max_roots as usize * BYTES_PER_ROOT_ENTRY;
+                if offset + BYTES_PER_ROOT_ENTRY > root_dir_len {
+                    return Err(From::from("root directory is full"));
+                }
+                let start = self.root_dir_offset() + offset as u64;
+                self.cache.borrow_mut().write(start, &bytes[..BYTES_PER_ROOT_ENTRY])?;
+            },
+            Dir::Chain(first_cluster) => {
+                let cluster_size = self.cluster_size();
+                let mut chain = self.cluster_chain(first_cluster);
+                if chain.is_empty() {
+                    return Err(From::from(format!(
+                        "directory at cluster {} has no clusters",
+                        first_cluster)));
+                }
+                while chain.len() * cluster_size <= offset {
+                    let new_cluster = self.allocate_clusters(1)?[0];
+                    self.write_cluster(new_cluster, &[])?;
+                    let last = chain[chain.len() - 1];
+                    self.set_fat_entry(last, new_cluster);
+                    chain.push(new_cluster);
+                }
+
+                let cluster_num = chain[offset / cluster_size];
+                let mut cluster = self.read_cluster(cluster_num)?;
+                let start = offset % cluster_size;
+                cluster[start .. start + BYTES_PER_ROOT_ENTRY]
+                    .clone_from_slice(bytes);
+                self.write_cluster(cluster_num, &cluster)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Location of the directory that `entry` refers to. A first cluster of
+    /// zero refers to the root directory, as in the `..` entry of a top
+    /// level directory.
+    pub fn entry_dir(&self, entry: &RootEntry) -> Dir {
+        match entry.entry_cluster() {
+            0 => self.root_dir_location(),
+            cluster_num => Dir::Chain(cluster_num),
+        }
+    }
+
     // TODO: Make iterator once "impl Trait" is stable.
-    /// Return all FAT root entries (including unused)
-    pub fn root_entries_all(&self) -> Vec<RootEntry> {
-        self.root_dir
+    /// Return all entries of a directory (including unused)
+    pub fn dir_entries_all(&self, dir: Dir)
+        -> Result<Vec<RootEntry>, Box<error::Error>>
+    {
+        Ok(self.dir_bytes(dir)?
             .chunks(BYTES_PER_ROOT_ENTRY)
-            .map(|chunk| {
-                let mut entry_bytes = [0; BYTES_PER_ROOT_ENTRY];
-                entry_bytes.clone_from_slice(chunk);
+            .map(entry_from_bytes)
+            .collect::<Vec<RootEntry>>())
+    }
 
-                let entry: RootEntry;
-                unsafe { entry = mem::transmute(entry_bytes); }
-                entry
-            })
-            .collect::<Vec<RootEntry>>()
+    /// Return the used entries of a directory along with their slots. Long
+    /// filename slots are not returned themselves, their name is attached
+    /// to the short entry following them instead.
+    pub fn read_dir(&self, dir: Dir) -> Result<Vec<DirEntry>, Box<error::Error>> {
+        self.read_dir_entries(dir, false)
+    }
+
+    /// Return the entries of a directory like `read_dir`, along with those
+    /// of deleted files and directories. Deleting an entry overwrites the
+    /// first character of its short name, which shows as `?`, and the long
+    /// name is not recovered.
+    pub fn read_dir_with_deleted(&self, dir: Dir)
+        -> Result<Vec<DirEntry>, Box<error::Error>>
+    {
+        self.read_dir_entries(dir, true)
+    }
+
+    fn read_dir_entries(&self, dir: Dir, deleted: bool)
+        -> Result<Vec<DirEntry>, Box<error::Error>>
+    {
+        let mut dir_entries = Vec::new();
+        let mut long_name = LongName::new();
+        let bytes = self.dir_bytes(dir)?;
+        for (index, slot) in bytes.chunks(BYTES_PER_ROOT_ENTRY).enumerate() {
+            let index = index as u16;
+            let entry = entry_from_bytes(slot);
+            if entry.rest_are_free() {
+                break;
+            } else if entry.is_free() {
+                long_name.reset();
+                if deleted && entry.is_deleted() && !lfn::is_lfn_slot(slot) {
+                    let mut shown = entry.clone();
+                    shown.filename[0] = '?' as u8;
+                    dir_entries.push(DirEntry {
+                        name: shown.filename().unwrap_or(shown.filename_full()),
+                        dir: dir,
+                        index: index,
+                        lfn_start: index,
+                        entry: entry,
+                    });
+                }
+                continue;
+            } else if lfn::is_lfn_slot(slot) {
+                long_name.push(index, slot);
+                continue;
+            }
+
+            // Fall back to the short name if the long name is incomplete or
+            // belongs to a different short entry.
+            let (name, lfn_start) = match long_name.finish(slot) {
+                Some(found) => found,
+                None => (
+                    entry.filename().unwrap_or(entry.filename_full()),
+                    index,
+                ),
+            };
+            dir_entries.push(DirEntry {
+                name: name,
+                dir: dir,
+                index: index,
+                lfn_start: lfn_start,
+                entry: entry,
+            });
+        }
+        Ok(dir_entries)
+    }
+
+    // TODO: Make iterator once "impl Trait" is stable.
+    /// Return all FAT root entries (including unused)
+    pub fn root_entries_all(&self) -> Result<Vec<RootEntry>, Box<error::Error>> {
+        self.dir_entries_all(self.root_dir_location())
     }
 
     // TODO: Make iterator once "impl Trait" is stable.
     /// Return used FAT root entries
-    pub fn root_entries(&self) -> Vec<RootEntry> {
-        self.root_dir
-            .chunks(BYTES_PER_ROOT_ENTRY)
-            .map(|chunk| {
-                let mut entry_bytes = [0; BYTES_PER_ROOT_ENTRY];
-                entry_bytes.clone_from_slice(chunk);
+    pub fn root_entries(&self) -> Result<Vec<RootEntry>, Box<error::Error>> {
+        Ok(self.read_dir(self.root_dir_location())?
+            .into_iter()
+            .map(|dir_entry| dir_entry.entry)
+            .collect::<Vec<RootEntry>>())
+    }
 
-                let entry: RootEntry;
-                unsafe { entry = mem::transmute(entry_bytes); }
-                entry
-            })
-            .filter(|entry| { entry.filename[0] != 0xe5 })
-            .take_while(|entry| { entry.filename[0] != 0 })
-            .collect::<Vec<RootEntry>>()
+    /// Find the entry called `name` directly within `dir`.
+    pub fn find_entry(&self, dir: Dir, name: &str)
+        -> Result<DirEntry, Box<error::Error>>
+    {
+        let name = name.to_lowercase();
+        for dir_entry in self.read_dir(dir)? {
+            if dir_entry.entry.is_volume_label() {
+                continue;
+            }
+
+            let short_name = dir_entry.entry.filename().ok()
+                .map(|short_name| short_name.to_lowercase());
+            if dir_entry.name.to_lowercase() == name
+                || short_name == Some(name.clone())
+            {
+                return Ok(dir_entry);
+            }
+        }
+
+        Err(From::from(format!("file {} not found", name)))
+    }
+
+    /// Resolve the directory at `path`. An empty path is the root directory.
+    pub fn lookup_dir(&self, path: &str) -> Result<Dir, Box<error::Error>> {
+        let mut dirs = vec![self.root_dir_location()];
+        for component in fat::split_path(path) {
+            match component {
+                "." => {},
+                ".." => {
+                    if dirs.len() > 1 {
+                        dirs.pop();
+                    }
+                },
+                name => {
+                    let dir = dirs[dirs.len() - 1];
+                    let dir_entry = self.find_entry(dir, name)?;
+                    if !dir_entry.entry.is_subdir() {
+                        return Err(From::from(format!(
+                            "{} is not a directory", name)));
+                    }
+                    dirs.push(self.entry_dir(&dir_entry.entry));
+                },
+            }
+        }
+        Ok(dirs[dirs.len() - 1])
+    }
+
+    /// Split `path` into the directory containing it and its final name.
+    fn lookup_parent<'a>(&self, path: &'a str)
+        -> Result<(Dir, &'a str), Box<error::Error>>
+    {
+        let mut components = fat::split_path(path);
+        let name = match components.pop() {
+            Some(".") | Some("..") | None =>
+                return Err(From::from(format!("bad path: \"{}\"", path))),
+            Some(name) => name,
+        };
+        let dir = self.lookup_dir(&components.join("/"))?;
+        Ok((dir, name))
+    }
+
+    /// Find the entry for the file or directory at `path`.
+    pub fn lookup(&self, path: &str) -> Result<DirEntry, Box<error::Error>> {
+        let (dir, name) = self.lookup_parent(path)?;
+        self.find_entry(dir, name)
     }
 
     /// Get the RootEntry for the specified file within the Image.
     pub fn get_file_entry(&self, filename: String)
         -> Result<RootEntry, Box<error::Error>>
     {
-        let entries = self.root_entries();
-        for entry in entries {
-            let entry_name: String;
-            match entry.filename() {
-                Ok(name) => entry_name = name,
-                Err(_) => continue,
-            }
+        self.lookup(&filename).map(|dir_entry| dir_entry.entry)
+    }
+
+    /// Remove a file from the image. Every cluster in its chain is freed and
+    /// the directory entry is marked as deleted.
+    pub fn remove_file(&mut self, filename: String)
+        -> Result<RootEntry, Box<error::Error>>
+    {
+        let mut dir_entry = self.lookup(&filename)?;
+        if dir_entry.entry.is_subdir() || dir_entry.entry.is_volume_label() {
+            return Err(From::from(format!("{} is not a file", filename)));
+        }
+
+        self.free_clusters(dir_entry.entry.entry_cluster());
+        dir_entry.entry.set_is_deleted();
+        self.save_file_entry(&dir_entry)?;
+        self.delete_lfn_slots(&dir_entry)?;
+        Ok(dir_entry.entry)
+    }
+
+    /// Mark the long filename slots belonging to an entry as deleted.
+    fn delete_lfn_slots(&mut self, dir_entry: &DirEntry)
+        -> Result<(), Box<error::Error>>
+    {
+        let bytes = self.dir_bytes(dir_entry.dir)?;
+        for index in dir_entry.lfn_start .. dir_entry.index {
+            let offset = index as usize * BYTES_PER_ROOT_ENTRY;
+            let mut slot = bytes[offset .. offset + BYTES_PER_ROOT_ENTRY].to_vec();
+            slot[0] = 0xe5;
+            self.write_dir_slot(dir_entry.dir, index, &slot)?;
+        }
+        Ok(())
+    }
+
+    /// Create a directory at `path`. The directory gets a zeroed cluster
+    /// holding its `.` and `..` entries.
+    pub fn create_dir(&mut self, path: &str)
+        -> Result<DirEntry, Box<error::Error>>
+    {
+        let mut dir_entry = self.create_file_entry(path.to_string(), 0)?;
+        let parent_cluster = match dir_entry.dir {
+            Dir::Chain(cluster_num) if
+                Dir::Chain(cluster_num) != self.root_dir_location() =>
+                cluster_num,
+            _ => 0,
+        };
+
+        let cluster_num = self.allocate_clusters(1)?[0];
+        self.write_cluster(cluster_num, &[])?;
+
+        // The `.` and `..` entries share the directory's timestamps.
+        let mut dot = RootEntry::new();
+        dot.creation_centiseconds = dir_entry.entry.creation_centiseconds;
+        dot.creation_time = dir_entry.entry.creation_time;
+        dot.creation_date = dir_entry.entry.creation_date;
+        dot.last_access_date = dir_entry.entry.last_access_date;
+        dot.last_write_time = dir_entry.entry.last_write_time;
+        dot.last_write_date = dir_entry.entry.last_write_date;
+        dot.filename[0] = '.' as u8;
+        dot.set_is_subdir(true);
+        dot.set_entry_cluster(cluster_num)?;
+        let mut dot_dot = dot.clone();
+        dot_dot.filename[1] = '.' as u8;
+        dot_dot.set_entry_cluster(parent_cluster)?;
 
-            if entry_name.to_lowercase() == filename.to_lowercase() {
-                return Ok(entry);
+        let dir = Dir::Chain(cluster_num);
+        for (index, entry) in vec![dot, dot_dot].into_iter().enumerate() {
+            self.save_file_entry(&DirEntry {
+                name: entry.filename()?,
+                dir: dir,
+                index: index as u16,
+                lfn_start: index as u16,
+                entry: entry,
+            })?;
+        }
+
+        dir_entry.entry.set_is_subdir(true);
+        dir_entry.entry.set_entry_cluster(cluster_num)?;
+        self.save_file_entry(&dir_entry)?;
+        Ok(dir_entry)
+    }
+
+    /// Create the directory at `path` along with any missing parents.
+    /// Directories that already exist are left alone.
+    pub fn create_dir_all(&mut self, path: &str)
+        -> Result<Dir, Box<error::Error>>
+    {
+        let mut dir_path = String::new();
+        let mut dir = self.root_dir_location();
+        for component in fat::split_path(path) {
+            dir_path = format!("{}/{}", dir_path, component);
+            dir = match self.lookup_dir(&dir_path) {
+                Ok(dir) => dir,
+                Err(_) => {
+                    let dir_entry = self.create_dir(&dir_path)?;
+                    self.entry_dir(&dir_entry.entry)
+                },
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Find `count` consecutive free slots in `dir`, returning the index of
+    /// the first one.
+    fn free_slots(&self, dir: Dir, count: usize)
+        -> Result<u16, Box<error::Error>>
+    {
+        let entries = self.dir_entries_all(dir)?;
+        let mut run = 0;
+        for (index, entry) in entries.iter().enumerate() {
+            run = if entry.is_free() { run + 1 } else { 0 };
+            if run == count {
+                return Ok((index + 1 - count) as u16);
             }
         }
 
-        Err(From::from(format!("file {} not found", filename)))
+        // Directories in a cluster chain are extended when the entries are
+        // saved, so a run at the end may continue past it.
+        let start = entries.len() - run;
+        match dir {
+            Dir::Chain(_) if start + count <= u16::max_value() as usize + 1 =>
+                Ok(start as u16),
+            _ => Err(From::from("no free entries")),
+        }
     }
 
-    /// Create a new RootEntry within the Image with the specified filename.
+    /// Create a new RootEntry within the Image at the specified path. Names
+    /// that don't fit in 8.3 get long filename slots and a generated short
+    /// name alias.
     pub fn create_file_entry(&self, filename: String, bytes: u32)
-        -> Result<(RootEntry, u16), Box<error::Error>>
+        -> Result<DirEntry, Box<error::Error>>
     {
-        match self.get_file_entry(filename.clone()) {
+        let (dir, name) = self.lookup_parent(&filename)?;
+        let name = name.trim_end_matches(|c| c == ' ' || c == '.');
+        match self.find_entry(dir, name) {
             Ok(_) => return Err(From::from("entry already exists")),
             Err(_) => {}
         }
 
-        for (index, e) in self.root_entries_all().iter().enumerate() {
-            if !e.is_free() { continue; }
+        let short_names = self.read_dir(dir)?
+            .iter()
+            .map(|dir_entry| {
+                let entry = &dir_entry.entry;
+                let mut short_name = entry.filename.to_vec();
+                short_name.extend(entry.extension.iter());
+                short_name
+            })
+            .collect::<Vec<Vec<u8>>>();
+        let short_name = lfn::short_name_for(name, &|candidate: &[u8]| {
+            short_names.iter().any(|s| &s[..] == candidate)
+        })?;
 
-            let mut entry = RootEntry::new();
-            entry.set_filename(filename)?;
-            entry.set_size(bytes)?;
-            return Ok((entry.clone(), index as u16));
-        }
+        let mut entry = RootEntry::new();
+        entry.filename.copy_from_slice(&short_name[..8]);
+        entry.extension.copy_from_slice(&short_name[8..]);
+        entry.set_size(bytes)?;
+        entry.set_times(self.now());
 
-        Err(From::from("no free entries"))
+        let lfn_count = if lfn::needs_long_name(name, &short_name) {
+            lfn::slot_count(name)
+        } else {
+            0
+        };
+        let lfn_start = self.free_slots(dir, lfn_count + 1)?;
+        Ok(DirEntry {
+            name: name.to_string(),
+            dir: dir,
+            index: lfn_start + lfn_count as u16,
+            lfn_start: lfn_start,
+            entry: entry,
+        })
     }
 
-    pub fn save_file_entry(&mut self, entry: RootEntry, index: u16)
+    /// Write a directory entry back to its slot, along with long filename
+    /// slots holding its name if it has any.
+    pub fn save_file_entry(&mut self, dir_entry: &DirEntry)
         -> Result<(), Box<error::Error>>
     {
         let entry_bytes: [u8; BYTES_PER_ROOT_ENTRY];
-        unsafe { entry_bytes = mem::transmute(entry); }
+        unsafe { entry_bytes = mem::transmute(dir_entry.entry.clone()); }
 
-        self.root_dir[
-            index as usize * BYTES_PER_ROOT_ENTRY ..
-            (index as usize + 1) * BYTES_PER_ROOT_ENTRY
-        ].clone_from_slice(&entry_bytes[..]);
-        Ok(())
+        if dir_entry.lfn_start < dir_entry.index {
+            let slots = lfn::lfn_slots(&dir_entry.name, &entry_bytes[..11]);
+            if slots.len() != (dir_entry.index - dir_entry.lfn_start) as usize {
+                return Err(From::from(format!(
+                    "name {} does not fit its long filename slots",
+                    dir_entry.name)));
+            }
+            for (index, slot) in (dir_entry.lfn_start ..).zip(slots.iter()) {
+                self.write_dir_slot(dir_entry.dir, index, slot)?;
+            }
+        }
+
+        self.write_dir_slot(dir_entry.dir, dir_entry.index, &entry_bytes[..])
+    }
+
+    /// Number of bytes in each data cluster.
+    pub fn cluster_size(&self) -> usize {
+        self.bpb_data.bytes_per_sector as usize
+            * self.bpb_data.sectors_per_cluster as usize
     }
 
+    /// Number of data clusters that are addressable by the FAT.
+    pub fn cluster_count(&self) -> usize {
+        let cluster_size = self.cluster_size();
+        if cluster_size == 0 {
+            return 0;
+        }
+        let in_data_area =
+            (self.length.saturating_sub(self.data_offset()) / cluster_size as u64) as usize;
+        let in_fat = self.fat_type.entries_in(self.fat.len()).saturating_sub(2);
+        in_data_area.min(in_fat)
+    }
+
+    /// Return every FAT entry (including the two reserved ones) as
+    /// (cluster number, value) pairs.
     pub fn fat_entries<'a>(&'a self)
-        -> Box<Iterator<Item=(usize, u16)> + 'a>
+        -> Box<Iterator<Item=(usize, u32)> + 'a>
     {
         Box::new(
-            self.fat_1
-            .windows(2)
-            .enumerate()
-            .filter_map(|(i, w)|
-                if i % 3 == 2 {
-                    None
-                } else {
-                    let val = if i % 3 == 0 {
-                        ((w[1] as u16 & 0xf) << 8) | w[0] as u16
-                    } else {
-                        (w[1] as u16 & 0xf0) | ((w[1] as u16) << 4)
-                    };
-                    Some((i, val))
-                }
-            )
+            (0 .. self.cluster_count() + 2)
+            .map(move |i| (i, self.get_fat_entry(i as u32)))
         )
     }
 
-    pub fn get_fat_entry(&self, cluster_num: u16) -> u16 {
-        let offset: usize = cluster_num as usize * 3 / 2;
-        let byte_1: u16 = self.fat_1[offset] as u16;
-        let byte_2: u16 = self.fat_1[offset + 1] as u16;
+    pub fn get_fat_entry(&self, cluster_num: u32) -> u32 {
+        self.fat_type.read_entry(&self.fat, cluster_num)
+    }
+
+    /// Set the FAT entry for `cluster_num` to `value`. The change is made to
+    /// the active FAT and reaches every copy of the FAT when flushed, unless
+    /// FAT32 mirroring is disabled in which case only the active FAT is
+    /// updated.
+    pub fn set_fat_entry(&mut self, cluster_num: u32, value: u32) {
+        let value = value & self.fat_type.entry_mask();
+        let (offset, len) = match self.fat_type {
+            FatType::Fat12 => {
+                let offset: usize = cluster_num as usize * 3 / 2;
+                let fat = &mut self.fat;
+                if cluster_num % 2 == 0 {
+                    fat[offset] = value as u8;
+                    fat[offset + 1] =
+                        (fat[offset + 1] & 0xf0) | (value >> 8) as u8;
+                } else {
+                    fat[offset] = (fat[offset] & 0x0f) | (value << 4) as u8;
+                    fat[offset + 1] = (value >> 4) as u8;
+                }
+                (offset, 2)
+            },
+            FatType::Fat16 => {
+                let offset: usize = cluster_num as usize * 2;
+                LittleEndian::write_u16(
+                    &mut self.fat[offset..offset + 2], value as u16);
+                (offset, 2)
+            },
+            FatType::Fat32 => {
+                // The top four bits are reserved and must be preserved.
+                let offset: usize = cluster_num as usize * 4;
+                let old = LittleEndian::read_u32(&self.fat[offset..offset + 4]);
+                LittleEndian::write_u32(
+                    &mut self.fat[offset..offset + 4],
+                    (old & 0xf0000000) | value);
+                (offset, 4)
+            },
+        };
+        self.mark_fat_dirty(offset, len);
+    }
 
-        if cluster_num % 2 == 0 { byte_1 | ((byte_2 & 0x0f) << 8) }
-        else                    { (byte_1 >> 4) | (byte_2 << 4) }
+    /// Follow the FAT from `first_cluster` and return every cluster in the
+    /// chain, in order. Stops at the end of chain marker, at any value
+    /// that is not a data cluster, or if the chain loops.
+    pub fn cluster_chain(&self, first_cluster: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster_num = first_cluster;
+        while fat::cluster_num_is_valid(self.fat_type, cluster_num)
+            && (cluster_num as usize) < self.cluster_count() + 2
+            && chain.len() <= self.cluster_count()
+        {
+            chain.push(cluster_num);
+            cluster_num = self.get_fat_entry(cluster_num);
+        }
+        chain
     }
 
     pub fn get_free_fat_entry(&self) -> Option<usize> {
         self.fat_entries()
+            .skip(2)
             .filter(|&(_, e)| e == 0)
-            .map(|(i, _)| i + 2)
+            .map(|(i, _)| i)
             .nth(0)
     }
 
-    pub fn write_data_sector(&mut self, sector: usize, data: &[u8])
-        -> Result<(), Box<error::Error>>
+    /// Allocate a chain of `count` free clusters. The clusters are linked
+    /// together in the FAT and the last one is marked as end of chain.
+    /// Returns the cluster numbers in chain order.
+    pub fn allocate_clusters(&mut self, count: usize)
+        -> Result<Vec<u32>, Box<error::Error>>
     {
-        let sector = sector - 2;
-        let bytes_per_sector = self.bpb_data.bytes_per_sector as usize;
-        let start_byte = bytes_per_sector * sector;
+        let chain = self.fat_entries()
+            .skip(2)
+            .filter(|&(_, e)| e == 0)
+            .map(|(i, _)| i as u32)
+            .take(count)
+            .collect::<Vec<u32>>();
+        if chain.len() < count {
+            return Err(From::from(format!(
+                "image ran out of space: need {} clusters, {} free",
+                count, chain.len())));
+        }
 
-        if start_byte >= self.data_area.len() {
-            return Err(From::from(format!("sector {} too high to write to", sector)))
+        for pair in chain.windows(2) {
+            self.set_fat_entry(pair[0], pair[1]);
+        }
+        if let Some(&last) = chain.last() {
+            let end_of_chain = self.fat_type.end_of_chain();
+            self.set_fat_entry(last, end_of_chain);
+        }
+        self.update_fs_info();
+        Ok(chain)
+    }
+
+    /// Mark every cluster in the chain starting at `first_cluster` as free.
+    pub fn free_clusters(&mut self, first_cluster: u32) {
+        for cluster_num in self.cluster_chain(first_cluster) {
+            self.set_fat_entry(cluster_num, 0);
+        }
+        self.update_fs_info();
+    }
+
+    /// Refresh the free cluster count and next free cluster hint in the
+    /// FAT32 FSInfo sector. Does nothing if there is no valid FSInfo sector.
+    pub(crate) fn update_fs_info(&mut self) {
+        if self.fat_type != FatType::Fat32 {
+            return;
+        }
+        let sector_size = self.sector_size();
+        let start = self.bpb_data.fs_info_sector as usize * sector_size;
+        if start == 0 || start + 512 > self.boot_sector.len() {
+            return;
+        }
+
+        let free_count = self.fat_entries()
+            .skip(2)
+            .filter(|&(_, e)| e == 0)
+            .count() as u32;
+        let next_free = self.get_free_fat_entry()
+            .map_or(0xffffffff, |c| c as u32);
+
+        {
+            let fs_info = &mut self.boot_sector[start .. start + 512];
+            if LittleEndian::read_u32(&fs_info[0..4]) != FS_INFO_LEAD_SIG
+                || LittleEndian::read_u32(&fs_info[484..488]) != FS_INFO_STRUCT_SIG
+            {
+                return;
+            }
+            LittleEndian::write_u32(&mut fs_info[488..492], free_count);
+            LittleEndian::write_u32(&mut fs_info[492..496], next_free);
+        }
+        self.mark_boot_dirty(start, 512);
+    }
+
+    /// Read the contents of the given data cluster.
+    pub fn read_cluster(&self, cluster_num: u32)
+        -> Result<Vec<u8>, Box<error::Error>>
+    {
+        if !fat::cluster_num_is_valid(self.fat_type, cluster_num)
+            || cluster_num as usize >= self.cluster_count() + 2
+        {
+            return Err(From::from(format!(
+                "cluster {} is outside the data area", cluster_num)));
+        }
+
+        let mut cluster = vec![0; self.cluster_size()];
+        self.cache.borrow_mut().read(self.cluster_offset(cluster_num), &mut cluster)?;
+        Ok(cluster)
+    }
+
+    /// Write `data` to the given data cluster, zero-filling the remainder.
+    pub fn write_cluster(&mut self, cluster_num: u32, data: &[u8])
+        -> Result<(), Box<error::Error>>
+    {
+        let cluster_size = self.cluster_size();
+        if !fat::cluster_num_is_valid(self.fat_type, cluster_num)
+            || cluster_num as usize >= self.cluster_count() + 2
+        {
+            return Err(From::from(format!(
+                "cluster {} is outside the data area", cluster_num)));
+        }
+        if data.len() > cluster_size {
+            return Err(From::from(format!(
+                "cannot write {} bytes to cluster {}", data.len(), cluster_num)));
         }
 
-        let mut target_slice = &mut self.data_area[start_byte..start_byte + data.len()];
-        target_slice.copy_from_slice(data);
+        let mut cluster = data.to_vec();
+        cluster.resize(cluster_size, 0);
+        self.cache.borrow_mut().write(self.cluster_offset(cluster_num), &cluster)?;
         Ok(())
     }
+
+    /// Read the contents of a file by following its cluster chain. The data
+    /// is truncated to the size recorded in the directory entry.
+    pub fn read_file(&self, entry: &RootEntry)
+        -> Result<Vec<u8>, Box<error::Error>>
+    {
+        let size = entry.file_size as usize;
+        let mut data = Vec::with_capacity(size);
+        let mut cluster_num = entry.entry_cluster();
+        while data.len() < size {
+            if !fat::cluster_num_is_valid(self.fat_type, cluster_num) {
+                return Err(From::from(format!(
+                    "cluster chain of {} ends after {} of {} bytes",
+                    entry.filename_full(), data.len(), size)));
+            }
+            data.extend(self.read_cluster(cluster_num)?);
+            cluster_num = self.get_fat_entry(cluster_num);
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Store `data` as a new file at the given path. Clusters are
+    /// allocated and linked for the file contents, and the directory entry
+    /// points at the start of the chain. The entry gets the archive bit, as
+    /// any new or changed file does.
+    pub fn write_file(&mut self, filename: String, data: &[u8])
+        -> Result<RootEntry, Box<error::Error>>
+    {
+        let mut reader = data;
+        self.write_file_from(filename, &mut reader, data.len() as u32)
+    }
+
+    /// Store the next `size` bytes of `reader` as a new file at the given
+    /// path, like `write_file`. The data is copied a cluster at a time, so
+    /// large host files needn't be held in memory.
+    pub fn write_file_from<R: Read>(&mut self, filename: String, reader: &mut R,
+                                    size: u32)
+        -> Result<RootEntry, Box<error::Error>>
+    {
+        let mut dir_entry = self.create_file_entry(filename, size)?;
+
+        let size = size as usize;
+        let cluster_size = self.cluster_size();
+        let count = (size + cluster_size - 1) / cluster_size;
+        let chain = self.allocate_clusters(count)?;
+        let mut chunk = vec![0; cluster_size];
+        for (i, &cluster_num) in chain.iter().enumerate() {
+            let len = cmp::min(cluster_size, size - i * cluster_size);
+            reader.read_exact(&mut chunk[..len]).map_err(|err| {
+                format!("{} of {} bytes read: {}", i * cluster_size, size, err)
+            })?;
+            self.write_cluster(cluster_num, &chunk[..len])?;
+        }
+
+        let first_cluster = chain.first().map_or(0, |&c| c);
+        dir_entry.entry.set_entry_cluster(first_cluster)?;
+        dir_entry.entry.set_is_archive(true);
+        self.save_file_entry(&dir_entry)?;
+        Ok(dir_entry.entry)
+    }
 }
@@ -0,0 +1,286 @@
+use std::error;
+
+/// Attribute value marking a VFAT long filename slot.
+pub const LFN_ATTRS: u8 = 0x0f;
+
+/// Number of UTF-16 code units stored in each long filename slot.
+pub const CHARS_PER_SLOT: usize = 13;
+
+// Flag in the sequence number marking the last (first stored) slot.
+const LAST_SLOT: u8 = 0x40;
+
+// Byte offsets of the UTF-16 name fragments within a long filename slot.
+const CHAR_OFFSETS: [usize; CHARS_PER_SLOT] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// Maximum length of a long filename in UTF-16 code units.
+const MAX_LONG_NAME: usize = 255;
+
+// Characters, besides letters and digits, that are allowed in short names.
+const SHORT_NAME_CHARS: &'static str = "$%'-_@~`!(){}^#&";
+
+// Characters that are not allowed in long names.
+const INVALID_CHARS: &'static str = "\"*/:<>?\\|";
+
+/// Checksum of an 11 byte short name, stored in each of its long filename
+/// slots to tie them to the short entry.
+pub fn checksum(short_name: &[u8]) -> u8 {
+    short_name[..11].iter().fold(0u8, |sum, &c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c)
+    })
+}
+
+/// Check whether a raw directory slot is a long filename slot.
+pub fn is_lfn_slot(slot: &[u8]) -> bool {
+    slot[11] & 0x3f == LFN_ATTRS
+}
+
+/// Collects the long filename slots that precede a short directory entry.
+#[derive(Debug,Default)]
+pub struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    next_seq: u8,
+    start: u16,
+}
+
+#[test]
+fn test_long_name() {
+    let short_name = b"BOOTX64 EFI";
+    let sum = checksum(short_name);
+    assert_eq!(sum, 0x1d);
+
+    let slots = lfn_slots("bootx64.efi", short_name);
+    assert_eq!(slots.len(), 1);
+    assert_eq!(slots[0][0], 1 | LAST_SLOT);
+    assert_eq!(slots[0][13], sum);
+
+    let mut long_name = LongName::new();
+    long_name.push(3, &slots[0]);
+    assert_eq!(long_name.finish(short_name), Some(("bootx64.efi".to_string(), 3)));
+
+    long_name.push(3, &slots[0]);
+    assert_eq!(long_name.finish(b"BOOTX64 EF "), None);
+
+    let name = "a very long file name.text";
+    let slots = lfn_slots(name, short_name);
+    assert_eq!(slots.len(), 2);
+    for (i, slot) in slots.iter().enumerate() {
+        long_name.push(10 + i as u16, slot);
+    }
+    assert_eq!(long_name.finish(short_name), Some((name.to_string(), 10)));
+}
+
+impl LongName {
+    pub fn new() -> LongName {
+        LongName::default()
+    }
+
+    /// Discard any slots collected so far.
+    pub fn reset(&mut self) {
+        self.units.clear();
+        self.next_seq = 0;
+    }
+
+    /// Add the long filename slot found at `index`. A slot that is out of
+    /// sequence discards the name collected so far.
+    pub fn push(&mut self, index: u16, slot: &[u8]) {
+        let seq = slot[0] & 0x1f;
+        if slot[0] & LAST_SLOT != 0 && seq != 0 {
+            self.units = vec![0xffff; seq as usize * CHARS_PER_SLOT];
+            self.checksum = slot[13];
+            self.start = index;
+        } else if seq == 0 || seq != self.next_seq || slot[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        let base = (seq as usize - 1) * CHARS_PER_SLOT;
+        for (i, &offset) in CHAR_OFFSETS.iter().enumerate() {
+            self.units[base + i] =
+                slot[offset] as u16 | (slot[offset + 1] as u16) << 8;
+        }
+        self.next_seq = seq - 1;
+    }
+
+    /// Finish the name for the short entry with the given 11 byte name.
+    /// Returns the long name and the index of its first slot if every slot
+    /// was seen and the checksum matches.
+    pub fn finish(&mut self, short_name: &[u8]) -> Option<(String, u16)> {
+        let complete = !self.units.is_empty() && self.next_seq == 0
+            && self.checksum == checksum(short_name);
+        let result = if complete {
+            let units = self.units
+                .iter()
+                .cloned()
+                .take_while(|&u| u != 0 && u != 0xffff)
+                .collect::<Vec<u16>>();
+            Some((String::from_utf16_lossy(&units), self.start))
+        } else {
+            None
+        };
+        self.reset();
+        result
+    }
+}
+
+#[test]
+fn test_short_name_for() {
+    let none = |_: &[u8]| false;
+    assert_eq!(&short_name_for("README.TXT", &none).unwrap(), b"README  TXT");
+    assert_eq!(&short_name_for("readme.txt", &none).unwrap(), b"README  TXT");
+    assert_eq!(&short_name_for("README.md.txt", &none).unwrap(), b"README~1TXT");
+    assert_eq!(&short_name_for("kernel-5.10.img", &none).unwrap(), b"KERNEL~1IMG");
+    assert_eq!(&short_name_for(".bashrc", &none).unwrap(), b"BASHRC~1   ");
+    assert_eq!(&short_name_for("na\u{ef}ve file", &none).unwrap(), b"NA_VEF~1   ");
+
+    let taken = |short_name: &[u8]| {
+        short_name == b"KERNEL~1IMG" || short_name == b"KERNEL~2IMG"
+    };
+    assert_eq!(&short_name_for("kernel-5.10.img", &taken).unwrap(), b"KERNEL~3IMG");
+
+    assert!(!needs_long_name("README.TXT", b"README  TXT"));
+    assert!(needs_long_name("readme.txt", b"README  TXT"));
+    assert_eq!(&short_name_for("my file.txt", &none).unwrap(), b"MYFILE~1TXT");
+    assert!(validate_long_name("a:b").is_err());
+}
+
+/// Check that `name` can be stored as a long filename.
+pub fn validate_long_name(name: &str) -> Result<(), Box<error::Error>> {
+    let bad_char = name.chars()
+        .any(|c| (c as u32) < 0x20 || INVALID_CHARS.contains(c));
+    if name.is_empty() || bad_char
+        || name.encode_utf16().count() > MAX_LONG_NAME
+    {
+        return Err(From::from(format!("bad filename: \"{}\"", name)));
+    }
+    Ok(())
+}
+
+/// Format an 11 byte short name as `NAME.EXT`.
+pub fn short_name_string(short_name: &[u8]) -> String {
+    let base = String::from_utf8_lossy(&short_name[..8]).trim_end().to_string();
+    let extension = String::from_utf8_lossy(&short_name[8..11]).trim_end().to_string();
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+/// Whether `name` has to be stored in long filename slots, because its
+/// short name alone would not reproduce it.
+pub fn needs_long_name(name: &str, short_name: &[u8]) -> bool {
+    short_name_string(short_name) != name
+}
+
+/// Convert a long name to characters allowed in a short name. Returns the
+/// converted characters and whether any had to be replaced or dropped.
+fn to_short_chars(name: &str) -> (Vec<u8>, bool) {
+    let mut lossy = name.contains(' ');
+    let chars = name.chars()
+        .filter(|&c| c != ' ')
+        .map(|c| {
+            let c = c.to_ascii_uppercase();
+            if c.is_ascii_alphanumeric() || c == '.' || SHORT_NAME_CHARS.contains(c) {
+                c as u8
+            } else {
+                lossy = true;
+                '_' as u8
+            }
+        })
+        .collect::<Vec<u8>>();
+    (chars, lossy)
+}
+
+/// Generate the short name alias for a long filename, following the
+/// algorithm Windows uses. `exists` reports whether a candidate short name
+/// is already taken in the directory, in which case a `~N` tail is tried.
+pub fn short_name_for<F>(name: &str, exists: &F)
+    -> Result<[u8; 11], Box<error::Error>>
+    where F: Fn(&[u8]) -> bool
+{
+    validate_long_name(name)?;
+    let (chars, lossy) = to_short_chars(name);
+    let stripped = chars.iter()
+        .cloned()
+        .skip_while(|&c| c == '.' as u8)
+        .collect::<Vec<u8>>();
+    let base_end = stripped.iter()
+        .position(|&c| c == '.' as u8)
+        .unwrap_or(stripped.len());
+    let ext_start = stripped.iter()
+        .rposition(|&c| c == '.' as u8)
+        .map_or(stripped.len(), |i| i + 1);
+
+    let base = stripped[..base_end].to_vec();
+    let extension = stripped[ext_start..].to_vec();
+    if base.is_empty() && extension.is_empty() {
+        return Err(From::from(format!("bad filename: \"{}\"", name)));
+    }
+
+    let mut short_name = [' ' as u8; 11];
+    for (i, &c) in extension.iter().take(3).enumerate() {
+        short_name[8 + i] = c;
+    }
+
+    // A name that already fits in 8.3 is used as is.
+    let fits = !lossy && stripped.len() == chars.len()
+        && base.len() <= 8 && extension.len() <= 3
+        && (base_end == stripped.len() || ext_start == base_end + 1);
+    if fits {
+        short_name[..base.len()].copy_from_slice(&base);
+        if !exists(&short_name) {
+            return Ok(short_name);
+        }
+    }
+
+    for n in 1 .. 1000000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut candidate = short_name;
+        for b in candidate[..8].iter_mut() {
+            *b = ' ' as u8;
+        }
+        candidate[..keep].copy_from_slice(&base[..keep]);
+        candidate[keep .. keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !exists(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    Err(From::from(format!("no free short name for \"{}\"", name)))
+}
+
+/// Number of long filename slots needed to store `name`.
+pub fn slot_count(name: &str) -> usize {
+    (name.encode_utf16().count() + CHARS_PER_SLOT - 1) / CHARS_PER_SLOT
+}
+
+/// Build the long filename slots for `name`, in the order they are stored
+/// in the directory (the last part of the name comes first).
+pub fn lfn_slots(name: &str, short_name: &[u8]) -> Vec<[u8; 32]> {
+    let count = slot_count(name);
+    let mut units = name.encode_utf16().collect::<Vec<u16>>();
+    if units.len() % CHARS_PER_SLOT != 0 {
+        units.push(0);
+    }
+    while units.len() < count * CHARS_PER_SLOT {
+        units.push(0xffff);
+    }
+
+    let sum = checksum(short_name);
+    (1 .. count + 1).rev()
+        .map(|seq| {
+            let mut slot = [0u8; 32];
+            slot[0] = seq as u8 | if seq == count { LAST_SLOT } else { 0 };
+            slot[11] = LFN_ATTRS;
+            slot[13] = sum;
+            let base = (seq - 1) * CHARS_PER_SLOT;
+            for (i, &offset) in CHAR_OFFSETS.iter().enumerate() {
+                slot[offset] = units[base + i] as u8;
+                slot[offset + 1] = (units[base + i] >> 8) as u8;
+            }
+            slot
+        })
+        .collect::<Vec<[u8; 32]>>()
+}
@@ -0,0 +1,192 @@
+extern crate byteorder;
+
+use std::collections::HashSet;
+use std::error;
+use std::io::{Read,Seek,SeekFrom};
+
+use self::byteorder::{LittleEndian,ByteOrder};
+
+use fat::{Partition,PartitionType};
+
+/// MBRs always address 512 byte sectors.
+pub const MBR_SECTOR_SIZE: u64 = 512;
+
+const ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const PRIMARY_COUNT: usize = 4;
+
+/// Partition type of the protective entry covering a GPT disk.
+pub const GPT_PROTECTIVE: u8 = 0xee;
+
+/// Partition type of an EFI system partition on an MBR disk.
+pub const EFI_SYSTEM: u8 = 0xef;
+
+// Logical partitions can't outnumber what fits on a sensible disk; stop
+// following a broken chain of extended boot records after this many.
+const MAX_LOGICAL: usize = 128;
+
+/// A partition table entry, with its start relative to the table it is in.
+#[derive(Clone,Copy,Debug)]
+struct Entry {
+    bootable: bool,
+    partition_type: u8,
+    start: u32,
+    sectors: u32,
+}
+
+#[test]
+fn test_read_mbr() {
+    let mut disk = vec![0; 64 * 512];
+    {
+        let mut put = |sector: usize, slot: usize, entry: (u8, u8, u32, u32)| {
+            let offset = sector * 512 + ENTRIES_OFFSET + slot * ENTRY_SIZE;
+            disk[offset] = entry.0;
+            disk[offset + 4] = entry.1;
+            LittleEndian::write_u32(&mut disk[offset + 8 .. offset + 12], entry.2);
+            LittleEndian::write_u32(&mut disk[offset + 12 .. offset + 16], entry.3);
+            disk[sector * 512 + 510] = 0x55;
+            disk[sector * 512 + 511] = 0xaa;
+        };
+        put(0, 0, (0x80, 0x0c, 2, 8));
+        put(0, 1, (0x00, 0x0f, 16, 40));
+        // Logical partitions are relative to their EBR, links to the next
+        // EBR are relative to the extended partition.
+        put(16, 0, (0x00, 0x06, 1, 7));
+        put(16, 1, (0x00, 0x05, 10, 12));
+        put(26, 0, (0x00, 0x83, 2, 10));
+    }
+
+    let partitions = read_mbr(&mut ::std::io::Cursor::new(&disk[..])).unwrap();
+    let summary = partitions.iter()
+        .map(|p| (p.number, p.start / 512, p.length / 512, p.partition_type))
+        .collect::<Vec<_>>();
+    assert_eq!(summary, vec![
+        (1, 2, 8, PartitionType::Mbr(0x0c)),
+        (2, 16, 40, PartitionType::Mbr(0x0f)),
+        (5, 17, 7, PartitionType::Mbr(0x06)),
+        (6, 28, 10, PartitionType::Mbr(0x83)),
+    ]);
+    assert!(partitions[0].bootable);
+
+    // A bare FAT boot sector is not a partition table.
+    let mut boot_sector = vec![0; 512];
+    for (i, b) in boot_sector[ENTRIES_OFFSET .. 510].iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    boot_sector[510] = 0x55;
+    boot_sector[511] = 0xaa;
+    assert!(read_mbr(&mut ::std::io::Cursor::new(&boot_sector[..])).is_err());
+}
+
+/// Whether `partition_type` marks an extended partition holding logical
+/// partitions.
+pub fn is_extended(partition_type: u8) -> bool {
+    partition_type == 0x05 || partition_type == 0x0f || partition_type == 0x85
+}
+
+/// Whether `partition_type` is one of the MBR types used for FAT.
+pub fn is_fat_type(partition_type: u8) -> bool {
+    match partition_type {
+        0x01 | 0x04 | 0x06 | 0x0b | 0x0c | 0x0e | 0x11 | 0x14 | 0x16
+            | 0x1b | 0x1c | 0x1e | 0xef => true,
+        _ => false,
+    }
+}
+
+/// Read the partition entries of the table in `sector`.
+fn read_table<D: Read + Seek>(device: &mut D, sector: u64)
+    -> Result<Vec<Entry>, Box<error::Error>>
+{
+    let mut table = [0; MBR_SECTOR_SIZE as usize];
+    device.seek(SeekFrom::Start(sector * MBR_SECTOR_SIZE))?;
+    device.read_exact(&mut table)?;
+    if table[510] != 0x55 || table[511] != 0xaa {
+        return Err(From::from(format!(
+            "no partition table signature in sector {}", sector)));
+    }
+
+    let mut entries = Vec::new();
+    for slot in 0 .. PRIMARY_COUNT {
+        let raw = &table[ENTRIES_OFFSET + slot * ENTRY_SIZE ..
+                         ENTRIES_OFFSET + (slot + 1) * ENTRY_SIZE];
+        if raw[0] != 0x00 && raw[0] != 0x80 {
+            return Err(From::from(format!(
+                "bad partition status {:#x} in sector {}", raw[0], sector)));
+        }
+        entries.push(Entry {
+            bootable: raw[0] == 0x80,
+            partition_type: raw[4],
+            start: LittleEndian::read_u32(&raw[8..12]),
+            sectors: LittleEndian::read_u32(&raw[12..16]),
+        });
+    }
+    Ok(entries)
+}
+
+fn is_used(entry: &Entry) -> bool {
+    entry.partition_type != 0 && entry.sectors != 0
+}
+
+/// Parse the MBR partition table of `device`, following the chain of
+/// extended boot records for logical partitions. Primary partitions are
+/// numbered 1 to 4 by their slot and logical partitions from 5 on.
+pub fn read_mbr<D: Read + Seek>(device: &mut D)
+    -> Result<Vec<Partition>, Box<error::Error>>
+{
+    let disk_sectors = device.seek(SeekFrom::End(0))? / MBR_SECTOR_SIZE;
+    let primary = read_table(device, 0)?;
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (slot, entry) in primary.iter().enumerate() {
+        if !is_used(entry) {
+            continue;
+        }
+        // The protective entry of a GPT disk may claim the largest size an
+        // MBR can hold, whatever the size of the disk.
+        let out_of_bounds = entry.start as u64 + entry.sectors as u64 > disk_sectors
+            && entry.partition_type != GPT_PROTECTIVE;
+        if entry.start == 0 || out_of_bounds {
+            return Err(From::from(format!(
+                "partition {} lies outside the disk", slot + 1)));
+        }
+        if is_extended(entry.partition_type) && extended.is_none() {
+            extended = Some(entry.start as u64);
+        }
+        partitions.push(Partition::new(
+            slot + 1, entry.start as u64 * MBR_SECTOR_SIZE,
+            entry.sectors as u64 * MBR_SECTOR_SIZE,
+            PartitionType::Mbr(entry.partition_type), entry.bootable));
+    }
+
+    if let Some(extended_start) = extended {
+        let mut visited = HashSet::new();
+        let mut logical_count = 0;
+        let mut ebr = extended_start;
+        while visited.insert(ebr) && visited.len() <= MAX_LOGICAL {
+            let entries = read_table(device, ebr)?;
+            let logical = entries[0];
+            if is_used(&logical) {
+                let start = ebr + logical.start as u64;
+                if start + logical.sectors as u64 > disk_sectors {
+                    return Err(From::from(format!(
+                        "logical partition at sector {} lies outside the disk",
+                        start)));
+                }
+                logical_count += 1;
+                let number = PRIMARY_COUNT + logical_count;
+                partitions.push(Partition::new(
+                    number, start * MBR_SECTOR_SIZE,
+                    logical.sectors as u64 * MBR_SECTOR_SIZE,
+                    PartitionType::Mbr(logical.partition_type), logical.bootable));
+            }
+
+            let next = entries[1];
+            if !is_used(&next) || !is_extended(next.partition_type) {
+                break;
+            }
+            ebr = extended_start + next.start as u64;
+        }
+    }
+    Ok(partitions)
+}
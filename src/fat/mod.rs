@@ -1,11 +1,44 @@
 mod image;
 mod root_entry;
 mod bios_param;
+mod fat_type;
+mod dir;
+mod lfn;
+mod format;
+mod geometry;
+mod device;
+mod cache;
+mod partition;
+mod mbr;
+mod gpt;
+mod check;
+mod repair;
+mod timestamp;
+mod host;
 
 pub use self::image::Image;
 pub use self::root_entry::RootEntry;
 pub use self::bios_param::BIOSParam;
+pub use self::fat_type::FatType;
+pub use self::format::ImageBuilder;
+pub use self::geometry::{FloppyGeometry,geometry_warning};
+// main.rs compiles this module as well and doesn't use all of its API.
+#[allow(unused_imports)]
+pub use self::geometry::FLOPPY_GEOMETRIES;
+pub use self::device::{BlockDevice,DeviceSlice};
+pub use self::partition::{Partition,PartitionType,partitions,find_partition,find_esp};
+#[allow(unused_imports)]
+pub use self::gpt::Guid;
+pub use self::check::{CheckReport,Problem,Severity};
+#[allow(unused_imports)]
+pub use self::repair::Repair;
+pub use self::timestamp::{Date,Time,DateTime};
+#[allow(unused_imports)]
+pub use self::host::ExtractReport;
+pub use self::dir::{Dir,DirEntry,split_path};
 
-pub fn cluster_num_is_valid(cluster_num: u16) -> bool {
-    2 <= cluster_num && cluster_num < 0xff0
+/// Check whether `cluster_num` refers to a data cluster, as opposed to a
+/// free, reserved, bad or end of chain marker.
+pub fn cluster_num_is_valid(fat_type: FatType, cluster_num: u32) -> bool {
+    2 <= cluster_num && cluster_num < (fat_type.entry_mask() & !0xf)
 }
@@ -0,0 +1,143 @@
+use std::error;
+use std::fmt;
+use std::io::{Read,Seek};
+
+use fat::BIOSParam;
+use fat::DeviceSlice;
+use fat::FatType;
+use fat::gpt;
+use fat::gpt::Guid;
+use fat::mbr;
+
+/// What a partition table says a partition holds.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum PartitionType {
+    /// MBR partition type byte.
+    Mbr(u8),
+    /// GPT partition type GUID.
+    Gpt(Guid),
+}
+
+/// A partition of a disk, with its position in bytes.
+#[derive(Clone,Debug)]
+pub struct Partition {
+    /// Number of the partition, counting from 1 as Linux does.
+    pub number: usize,
+    pub start: u64,
+    pub length: u64,
+    pub partition_type: PartitionType,
+    pub bootable: bool,
+    /// Name of the partition; only GPT partitions have one.
+    pub name: String,
+}
+
+impl Partition {
+    pub fn new(number: usize, start: u64, length: u64,
+               partition_type: PartitionType, bootable: bool) -> Partition
+    {
+        Partition {
+            number: number,
+            start: start,
+            length: length,
+            partition_type: partition_type,
+            bootable: bootable,
+            name: String::new(),
+        }
+    }
+
+    /// Whether the partition table marks the partition as holding FAT.
+    /// Basic data partitions on GPT disks may hold FAT or NTFS; use
+    /// `fat_type` to look at the contents.
+    pub fn is_fat(&self) -> bool {
+        match self.partition_type {
+            PartitionType::Mbr(partition_type) => mbr::is_fat_type(partition_type),
+            PartitionType::Gpt(guid) =>
+                guid == gpt::ESP_GUID || guid == gpt::BASIC_DATA_GUID,
+        }
+    }
+
+    /// Whether this is an EFI system partition.
+    pub fn is_esp(&self) -> bool {
+        match self.partition_type {
+            PartitionType::Mbr(partition_type) => partition_type == mbr::EFI_SYSTEM,
+            PartitionType::Gpt(guid) => guid == gpt::ESP_GUID,
+        }
+    }
+
+    /// Whether the partition only holds other partitions.
+    pub fn is_container(&self) -> bool {
+        match self.partition_type {
+            PartitionType::Mbr(partition_type) =>
+                mbr::is_extended(partition_type) || partition_type == mbr::GPT_PROTECTIVE,
+            PartitionType::Gpt(_) => false,
+        }
+    }
+
+    /// Type of the FAT filesystem the partition holds, judging by its boot
+    /// sector, or `None` if it doesn't hold one.
+    pub fn fat_type<D: Read + Seek>(&self, device: &mut D) -> Option<FatType> {
+        let mut slice = self.slice(device);
+        match BIOSParam::read_from(&mut slice) {
+            Ok(ref bpb) if bpb.is_valid() => Some(bpb.fat_type()),
+            _ => None,
+        }
+    }
+
+    /// View of the partition's bytes within `device`.
+    pub fn slice<D: Seek>(&self, device: D) -> DeviceSlice<D> {
+        DeviceSlice::new(device, self.start, self.length)
+    }
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PartitionType::Mbr(partition_type) => write!(f, "{:#04x}", partition_type),
+            PartitionType::Gpt(guid) => match guid.type_name() {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", guid),
+            },
+        }
+    }
+}
+
+/// List the partitions of a disk image. Disks with a protective MBR list
+/// their GPT partitions instead.
+pub fn partitions<D: Read + Seek>(device: &mut D)
+    -> Result<Vec<Partition>, Box<error::Error>>
+{
+    let partitions = mbr::read_mbr(device)?;
+    let protective = partitions.iter().any(|partition| {
+        partition.partition_type == PartitionType::Mbr(mbr::GPT_PROTECTIVE)
+    });
+    if protective {
+        gpt::read_gpt(device)
+    } else {
+        Ok(partitions)
+    }
+}
+
+/// Find the EFI system partition of a disk image.
+pub fn find_esp<D: Read + Seek>(device: &mut D)
+    -> Result<Partition, Box<error::Error>>
+{
+    match partitions(device)?.into_iter().find(Partition::is_esp) {
+        Some(partition) => Ok(partition),
+        None => Err(From::from("disk has no EFI system partition")),
+    }
+}
+
+/// Find partition `number` of a disk image.
+pub fn find_partition<D: Read + Seek>(device: &mut D, number: usize)
+    -> Result<Partition, Box<error::Error>>
+{
+    let partition = partitions(device)?
+        .into_iter()
+        .find(|partition| partition.number == number);
+    match partition {
+        Some(ref partition) if partition.is_container() => Err(From::from(format!(
+            "partition {} is an extended partition", number))),
+        Some(partition) => Ok(partition),
+        None => Err(From::from(format!("disk has no partition {}", number))),
+    }
+}
@@ -0,0 +1,308 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+
+use fat::{CheckReport,Dir,Image,Problem};
+
+// Most numbered FOUND.nnn directories and FILEnnnn.CHK files, as CHKDSK.
+const MAX_FOUND_DIRS: usize = 1000;
+const MAX_FOUND_FILES: usize = 10000;
+
+/// A change that fixes a problem found by `Image::check`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Repair {
+    /// End the chain of `path` at `cluster`, dropping whatever the chain
+    /// pointed to after it.
+    EndChain { path: String, cluster: u32 },
+    /// Give `path` its own copy of its chain from the cross-linked
+    /// `cluster` on, which it shares with `other`.
+    CopyChain { path: String, cluster: u32, other: String },
+    /// Set the size of the file at `path` to the `size` bytes its chain
+    /// holds.
+    SetSize { path: String, size: u32 },
+    /// Keep the lost chain of `clusters` clusters at `first_cluster` as the
+    /// file `path`.
+    RecoverChain { first_cluster: u32, clusters: usize, path: String },
+    /// Free the lost chain of `clusters` clusters at `first_cluster`.
+    FreeChain { first_cluster: u32, clusters: usize },
+    /// Copy the active FAT over FAT `fat`.
+    SyncFat { fat: usize },
+}
+
+#[test]
+fn test_repair() {
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.create_dir("DIR").unwrap();
+    image.write_file("DIR/A.TXT".to_string(), &[1; 1500]).unwrap();
+    image.write_file("B.TXT".to_string(), &[2; 600]).unwrap();
+    image.write_file("C.TXT".to_string(), &[3; 1500]).unwrap();
+    let a = image.get_file_entry("DIR/A.TXT".to_string()).unwrap().entry_cluster();
+    let b = image.get_file_entry("B.TXT".to_string()).unwrap().entry_cluster();
+    let c = image.get_file_entry("C.TXT".to_string()).unwrap().entry_cluster();
+    let lost = image.allocate_clusters(2).unwrap();
+    image.write_cluster(lost[0], b"lost").unwrap();
+    image.set_fat_entry(b + 1, a + 1);
+    image.set_fat_entry(c + 1, c);
+
+    let report = image.check().unwrap();
+    let repairs = image.plan_repairs(&report, true).unwrap();
+    assert_eq!(repairs, vec![
+        Repair::EndChain { path: "/C.TXT".to_string(), cluster: c + 1 },
+        Repair::CopyChain {
+            path: "/DIR/A.TXT".to_string(), cluster: a + 1, other: "/B.TXT".to_string() },
+        Repair::SetSize { path: "/B.TXT".to_string(), size: 2048 },
+        Repair::SetSize { path: "/C.TXT".to_string(), size: 1024 },
+        Repair::RecoverChain {
+            first_cluster: c + 2, clusters: 1, path: "FOUND.000/FILE0000.CHK".to_string() },
+        Repair::RecoverChain {
+            first_cluster: lost[0], clusters: 2, path: "FOUND.000/FILE0001.CHK".to_string() },
+    ]);
+    for repair in repairs.iter() {
+        image.apply_repair(repair).unwrap();
+    }
+    let report = image.check().unwrap();
+    assert_eq!(report.problems, vec![]);
+
+    let a_entry = image.get_file_entry("DIR/A.TXT".to_string()).unwrap();
+    assert_eq!(image.read_file(&a_entry).unwrap(), vec![1; 1500]);
+    let found = image.get_file_entry("FOUND.000/FILE0001.CHK".to_string()).unwrap();
+    assert_eq!(&image.read_file(&found).unwrap()[..4], b"lost");
+
+    // Lost chains can be freed instead.
+    let lost = image.allocate_clusters(3).unwrap();
+    let report = image.check().unwrap();
+    let repairs = image.plan_repairs(&report, false).unwrap();
+    assert_eq!(repairs, vec![Repair::FreeChain { first_cluster: lost[0], clusters: 3 }]);
+    image.apply_repair(&repairs[0]).unwrap();
+    assert!(lost.iter().all(|&cluster_num| image.get_fat_entry(cluster_num) == 0));
+}
+
+#[test]
+fn test_repair_cross_linked_dir() {
+    let mut image = ::fat::ImageBuilder::new(1440 * 1024).build().unwrap();
+    image.write_file("F.TXT".to_string(), &[1; 1024]).unwrap();
+    image.create_dir("DIR").unwrap();
+    image.create_dir("DIR/SUB").unwrap();
+    image.write_file("DIR/SUB/X.TXT".to_string(), b"x").unwrap();
+    let f = image.get_file_entry("F.TXT".to_string()).unwrap().entry_cluster();
+    let dir = image.get_file_entry("DIR".to_string()).unwrap().entry_cluster();
+    image.set_fat_entry(f + 1, dir);
+
+    let report = image.check().unwrap();
+    let repairs = image.plan_repairs(&report, true).unwrap();
+    assert!(repairs.contains(&Repair::CopyChain {
+        path: "/DIR".to_string(), cluster: dir, other: "/F.TXT".to_string() }));
+    for repair in repairs.iter() {
+        image.apply_repair(repair).unwrap();
+    }
+    assert_eq!(image.check().unwrap().problems, vec![]);
+
+    let copy = image.get_file_entry("DIR".to_string()).unwrap().entry_cluster();
+    assert!(copy != dir);
+    let sub = image.lookup_dir("DIR/SUB").unwrap();
+    let dot_dot = image.find_entry(sub, "..").unwrap();
+    assert_eq!(dot_dot.entry.entry_cluster(), copy);
+    let x = image.get_file_entry("DIR/SUB/X.TXT".to_string()).unwrap();
+    assert_eq!(image.read_file(&x).unwrap(), b"x");
+}
+
+impl Repair {
+    /// Repairs are made in this order, so that chains are whole and
+    /// unshared before sizes are matched to them.
+    fn stage(&self) -> usize {
+        match *self {
+            Repair::EndChain { .. } => 0,
+            Repair::CopyChain { .. } => 1,
+            Repair::SetSize { .. } => 2,
+            Repair::RecoverChain { .. } | Repair::FreeChain { .. } => 3,
+            Repair::SyncFat { .. } => 4,
+        }
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Repair::EndChain { ref path, cluster } =>
+                write!(f, "{}: end the cluster chain at cluster {}", path, cluster),
+            Repair::CopyChain { ref path, cluster, ref other } =>
+                write!(f, "{}: copy the clusters shared with {} from cluster {} on",
+                    path, other, cluster),
+            Repair::SetSize { ref path, size } =>
+                write!(f, "{}: set the size to {} bytes", path, size),
+            Repair::RecoverChain { first_cluster, clusters, ref path } =>
+                write!(f, "save the lost chain of {} clusters at cluster {} as {}",
+                    clusters, first_cluster, path),
+            Repair::FreeChain { first_cluster, clusters } =>
+                write!(f, "free the lost chain of {} clusters at cluster {}",
+                    clusters, first_cluster),
+            Repair::SyncFat { fat } =>
+                write!(f, "copy the active FAT over FAT {}", fat),
+        }
+    }
+}
+
+impl Image {
+    /// Work out the repairs for the problems in `report`, a check of this
+    /// image. Lost chains are recovered into `FOUND.nnn/FILEnnnn.CHK` files
+    /// if `recover_lost` is set and freed otherwise. Invalid entries and
+    /// bad `.` and `..` entries are left alone. Nothing is changed until
+    /// the repairs are passed to `apply_repair`.
+    pub fn plan_repairs(&self, report: &CheckReport, recover_lost: bool)
+        -> Result<Vec<Repair>, Box<error::Error>>
+    {
+        let found_dir = if recover_lost {
+            (0 .. MAX_FOUND_DIRS)
+                .map(|n| format!("FOUND.{:03}", n))
+                .find(|name| self.lookup(name).is_err())
+        } else {
+            None
+        };
+        let mut found_files = 0;
+
+        let cluster_size = self.cluster_size() as u64;
+        let mut repairs = Vec::new();
+        for problem in report.problems.iter() {
+            let repair = match *problem {
+                Problem::FatMismatch { fat, mirrored: true, .. } =>
+                    Repair::SyncFat { fat: fat },
+                Problem::BadChain { ref path, cluster, .. }
+                    | Problem::ChainLoop { ref path, cluster, .. } =>
+                    Repair::EndChain { path: path.clone(), cluster: cluster },
+                Problem::CrossLink { cluster, ref first, ref second } =>
+                    Repair::CopyChain {
+                        path: second.clone(),
+                        cluster: cluster,
+                        other: first.clone(),
+                    },
+                Problem::SizeMismatch { ref path, clusters, .. } =>
+                    Repair::SetSize {
+                        path: path.clone(),
+                        size: cmp::min(clusters as u64 * cluster_size,
+                                       u32::max_value() as u64) as u32,
+                    },
+                Problem::LostChain { first_cluster, clusters } => match found_dir {
+                    Some(ref found_dir) if found_files < MAX_FOUND_FILES => {
+                        found_files += 1;
+                        Repair::RecoverChain {
+                            first_cluster: first_cluster,
+                            clusters: clusters,
+                            path: format!("{}/FILE{:04}.CHK", found_dir, found_files - 1),
+                        }
+                    },
+                    _ if recover_lost =>
+                        return Err(From::from("no room to recover lost chains")),
+                    _ => Repair::FreeChain {
+                        first_cluster: first_cluster,
+                        clusters: clusters,
+                    },
+                },
+                _ => continue,
+            };
+            repairs.push(repair);
+        }
+        repairs.sort_by_key(Repair::stage);
+        Ok(repairs)
+    }
+
+    /// Make a repair worked out by `plan_repairs`. Repairs must be applied
+    /// in the order they were planned. The FAT is only written on `flush`.
+    pub fn apply_repair(&mut self, repair: &Repair)
+        -> Result<(), Box<error::Error>>
+    {
+        match *repair {
+            Repair::EndChain { cluster, .. } => {
+                let end_of_chain = self.fat_type().end_of_chain();
+                self.set_fat_entry(cluster, end_of_chain);
+            },
+            Repair::CopyChain { ref path, cluster, .. } =>
+                self.copy_chain(path, cluster)?,
+            Repair::SetSize { ref path, size } => {
+                let mut dir_entry = self.lookup(path)?;
+                dir_entry.entry.set_size(size)?;
+                self.save_file_entry(&dir_entry)?;
+            },
+            Repair::RecoverChain { first_cluster, clusters, ref path } => {
+                if let Some(slash) = path.rfind('/') {
+                    self.create_dir_all(&path[.. slash])?;
+                }
+                let size = clusters as u64 * self.cluster_size() as u64;
+                let mut dir_entry = self.create_file_entry(
+                    path.clone(), cmp::min(size, u32::max_value() as u64) as u32)?;
+                let last = self.lost_chain(first_cluster, clusters)[clusters - 1];
+                let end_of_chain = self.fat_type().end_of_chain();
+                self.set_fat_entry(last, end_of_chain);
+                dir_entry.entry.set_entry_cluster(first_cluster)?;
+                self.save_file_entry(&dir_entry)?;
+            },
+            Repair::FreeChain { first_cluster, clusters } => {
+                for cluster_num in self.lost_chain(first_cluster, clusters) {
+                    self.set_fat_entry(cluster_num, 0);
+                }
+                self.update_fs_info();
+            },
+            Repair::SyncFat { .. } => self.sync_fats(),
+        }
+        Ok(())
+    }
+
+    /// The first `clusters` clusters of the chain at `first_cluster`,
+    /// however the chain goes on.
+    fn lost_chain(&self, first_cluster: u32, clusters: usize) -> Vec<u32> {
+        let mut chain = vec![first_cluster];
+        while chain.len() < clusters {
+            let next = self.get_fat_entry(chain[chain.len() - 1]);
+            chain.push(next);
+        }
+        chain
+    }
+
+    /// Move the chain of `path` from `cluster` on to newly allocated
+    /// clusters holding the same data.
+    fn copy_chain(&mut self, path: &str, cluster: u32)
+        -> Result<(), Box<error::Error>>
+    {
+        let mut dir_entry = self.lookup(path)?;
+        let chain = self.cluster_chain(dir_entry.entry.entry_cluster());
+        let start = match chain.iter().position(|&c| c == cluster) {
+            Some(start) => start,
+            None => return Err(From::from(format!(
+                "cluster {} is not in the chain of {}", cluster, path))),
+        };
+
+        let copies = self.allocate_clusters(chain.len() - start)?;
+        for (&from, &to) in chain[start ..].iter().zip(copies.iter()) {
+            let data = self.read_cluster(from)?;
+            self.write_cluster(to, &data)?;
+        }
+
+        if start > 0 {
+            self.set_fat_entry(chain[start - 1], copies[0]);
+            return Ok(());
+        }
+        dir_entry.entry.set_entry_cluster(copies[0])?;
+        self.save_file_entry(&dir_entry)?;
+        if dir_entry.entry.is_subdir() {
+            // The copy's `.` entry and the `..` entries of its
+            // subdirectories have to point at the copy.
+            for mut child in self.read_dir(Dir::Chain(copies[0]))? {
+                if child.name == "." {
+                    child.entry.set_entry_cluster(copies[0])?;
+                    self.save_file_entry(&child)?;
+                } else if child.name != ".." && child.entry.is_subdir()
+                    && child.entry.entry_cluster() != 0
+                {
+                    let subdir = self.entry_dir(&child.entry);
+                    let dot_dot = self.read_dir(subdir)?
+                        .into_iter()
+                        .find(|d| d.name == "..");
+                    if let Some(mut dot_dot) = dot_dot {
+                        dot_dot.entry.set_entry_cluster(copies[0])?;
+                        self.save_file_entry(&dot_dot)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
 use std::error;
 use std::fmt;
 use std::fmt::Debug;
+use std::fs;
+use std::io;
+
+use fat::{Date,DateTime,Time};
 
 #[derive(Clone)]
 #[repr(C)]
@@ -8,7 +12,9 @@ pub struct RootEntry {
     pub filename:  [u8; 8],
     pub extension: [u8; 3],
     attrs: u8,
-    reserved: u16,
+    reserved: u8,
+    /// 10 ms steps to add to `creation_time`, from 0 to 199.
+    pub creation_centiseconds: u8,
     pub creation_time: u16,
     pub creation_date: u16,
     pub last_access_date: u16,
@@ -19,6 +25,18 @@ pub struct RootEntry {
     pub file_size: u32, // in bytes
 }
 
+#[test]
+fn test_set_entry_cluster() {
+    let mut entry = RootEntry::new();
+    entry.set_entry_cluster(0x12345).unwrap();
+    assert_eq!(entry.entry_cluster(), 0x12345);
+
+    // Moving the entry to a low cluster has to clear the old high half.
+    entry.set_entry_cluster(0x42).unwrap();
+    assert_eq!(entry.hi_first_lcluster, 0);
+    assert_eq!(entry.entry_cluster(), 0x42);
+}
+
 #[allow(dead_code)]
 impl RootEntry {
     /// Create a new empty FAT root directory entry.
@@ -28,6 +46,7 @@ impl RootEntry {
             extension: [' ' as u8; 3],
             attrs: 0,
             reserved: 0,
+            creation_centiseconds: 0,
             creation_time: 0,
             creation_date: 0,
             last_access_date: 0,
@@ -39,15 +58,23 @@ impl RootEntry {
         }
     }
 
-    /// Get the filename
+    /// Get the filename. The dot is omitted when there is no extension, as
+    /// for most directories.
     pub fn filename(&self) -> Result<String, Box<error::Error>> {
         let mut my_fn = self.filename.to_vec();
         let mut name = my_fn
             .drain(..)
             .take_while(|&c| c != ' ' as u8)
             .collect::<Vec<u8>>();
-        name.push('.' as u8);
-        name.extend(self.extension.iter());
+        let extension = self.extension
+            .iter()
+            .cloned()
+            .take_while(|&c| c != ' ' as u8)
+            .collect::<Vec<u8>>();
+        if !extension.is_empty() {
+            name.push('.' as u8);
+            name.extend(extension);
+        }
 
         match String::from_utf8(name) {
             Ok(s) => Ok(s),
@@ -59,8 +86,14 @@ impl RootEntry {
     pub fn set_filename(&mut self, filename: String)
         -> Result<(), Box<error::Error>>
     {
-        let parts: Vec<_> = filename.split('.').collect();
-        if parts.len() != 2 || parts[0].len() > 8 || parts[1].len() > 3 {
+        let mut parts: Vec<_> = filename.split('.').collect();
+        if parts.len() == 1 {
+            // No extension, as is usual for directories
+            parts.push("");
+        }
+        if parts.len() != 2 || parts[0].is_empty()
+            || parts[0].len() > 8 || parts[1].len() > 3
+        {
             return Err(From::from(format!("bad filename: \"{}\"", filename)));
         }
 
@@ -91,14 +124,101 @@ impl RootEntry {
     pub fn set_entry_cluster(&mut self, cluster_num: u32)
         -> Result<(), Box<error::Error>>
     {
+        // The high half is only used on FAT32 and is zero elsewhere.
         self.lo_first_lcluster = (cluster_num & 0xFFFF) as u16;
-        if cluster_num > u16::max_value() as u32 {
-            // Only supported on FAT32
-            self.hi_first_lcluster = (cluster_num >> 16) as u16;
+        self.hi_first_lcluster = (cluster_num >> 16) as u16;
+        Ok(())
+    }
+
+    /// When the file was created, if the entry records a valid time.
+    pub fn created(&self) -> Option<DateTime> {
+        let date = Date::from_dos(self.creation_date)?;
+        let time = Time::from_dos(self.creation_time, self.creation_centiseconds)?;
+        Some(DateTime::new(date, time))
+    }
+
+    /// Set the creation time, which is kept to 10 ms.
+    pub fn set_created(&mut self, created: DateTime) {
+        let (time, centiseconds) = created.time.to_dos();
+        self.creation_date = created.date.to_dos();
+        self.creation_time = time;
+        self.creation_centiseconds = centiseconds;
+    }
+
+    /// When the file was last written, if the entry records a valid time.
+    pub fn modified(&self) -> Option<DateTime> {
+        let date = Date::from_dos(self.last_write_date)?;
+        let time = Time::from_dos(self.last_write_time, 0)?;
+        Some(DateTime::new(date, time))
+    }
+
+    /// Set the last write time, which is kept to 2 seconds, rounding down.
+    pub fn set_modified(&mut self, modified: DateTime) {
+        self.last_write_date = modified.date.to_dos();
+        self.last_write_time = modified.time.to_dos().0;
+    }
+
+    /// The day the file was last accessed, if the entry records one. FAT
+    /// keeps no time of day for accesses.
+    pub fn accessed(&self) -> Option<Date> {
+        Date::from_dos(self.last_access_date)
+    }
+
+    pub fn set_accessed(&mut self, accessed: Date) {
+        self.last_access_date = accessed.to_dos();
+    }
+
+    /// Set the creation, last write and last access times all to `time`.
+    pub fn set_times(&mut self, time: DateTime) {
+        self.set_created(time);
+        self.set_modified(time);
+        self.set_accessed(time.date);
+    }
+
+    /// Take the timestamps and read-only flag of a host file. The creation
+    /// time falls back to the last write time on hosts that don't record
+    /// it.
+    pub fn copy_host_metadata(&mut self, metadata: &fs::Metadata) {
+        if let Ok(modified) = metadata.modified() {
+            let modified = DateTime::from_system_time(modified);
+            self.set_modified(modified);
+            self.set_created(metadata.created()
+                .map_or(modified, DateTime::from_system_time));
+        }
+        if let Ok(accessed) = metadata.accessed() {
+            self.set_accessed(DateTime::from_system_time(accessed).date);
+        }
+        self.set_is_read_only(metadata.permissions().readonly());
+    }
+
+    /// Give a host file the last write time and read-only flag of this
+    /// entry.
+    pub fn restore_host_metadata(&self, file: &fs::File) -> io::Result<()> {
+        if let Some(modified) = self.modified() {
+            file.set_modified(modified.to_system_time())?;
+        }
+        if self.is_read_only() {
+            let mut permissions = file.metadata()?.permissions();
+            permissions.set_readonly(true);
+            file.set_permissions(permissions)?;
         }
         Ok(())
     }
 
+    /// Raw attribute byte.
+    pub fn attributes(&self) -> u8 {
+        self.attrs
+    }
+
+    /// The attributes as `RHSVDA` flags, read-only, hidden, system, volume
+    /// label, directory and archive, with `-` for those that are unset.
+    pub fn attribute_flags(&self) -> String {
+        "RHSVDA".chars()
+            .enumerate()
+            .map(|(bit, flag)| if self.attrs & 1 << bit != 0 { flag } else { '-' })
+            .collect()
+    }
+
     pub fn is_read_only(&self)    -> bool { self.attrs & 0x01 == 0x01 }
     pub fn is_hidden(&self)       -> bool { self.attrs & 0x02 == 0x02 }
     pub fn is_system(&self)       -> bool { self.attrs & 0x04 == 0x04 }
@@ -114,6 +234,15 @@ impl RootEntry {
         self.filename[0] == 0
     }
 
+    pub fn is_deleted(&self) -> bool {
+        self.filename[0] == 0xe5
+    }
+
+    /// Mark the entry as deleted, freeing its slot in the directory.
+    pub fn set_is_deleted(&mut self) {
+        self.filename[0] = 0xe5;
+    }
+
     pub fn set_is_read_only(&mut self, on: bool) {
         self.attrs = (self.attrs & !0x01) | if on { 0x01 } else { 0 }
     }
@@ -161,14 +290,17 @@ impl Debug for RootEntry {
                 &self.filename().unwrap_or("bad filename".to_string())
             )
             .field("attrs",                 &self.attrs)
-            .field("creation_time",         &self.creation_time)
-            .field("creation_date",         &self.creation_date)
-            .field("last_access_date",      &self.last_access_date)
+            .field("created",               &describe_time(self.created()))
+            .field("last_accessed",         &describe_time(self.accessed()))
             .field("hi_first_lcluster",     &self.hi_first_lcluster)
-            .field("last_write_time",       &self.last_write_time)
-            .field("last_write_date",       &self.last_write_date)
+            .field("last_modified",         &describe_time(self.modified()))
             .field("lo_first_lcluster",      &self.lo_first_lcluster)
             .field("file_size",             &format!("{:#x}", self.file_size))
             .finish()
     }
 }
+
+/// A decoded timestamp for display, or a note that the entry has none.
+fn describe_time<T: fmt::Display>(time: Option<T>) -> String {
+    time.map_or("unset".to_string(), |time| time.to_string())
+}
@@ -0,0 +1,307 @@
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+/// First and last year a directory entry can hold.
+pub const MIN_YEAR: u16 = 1980;
+pub const MAX_YEAR: u16 = 2107;
+
+/// A calendar date as stored in a directory entry, with the year counted
+/// from 1980, the month and the day packed into 16 bits.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// A time of day as stored in a directory entry. The packed format only
+/// holds even seconds; creation times have an extra byte counting 10 ms
+/// steps that fills in the rest.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A date and time of day, in whatever time zone the writer used; FAT
+/// doesn't record one. Conversions to and from host times use UTC.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct DateTime {
+    pub date: Date,
+    pub time: Time,
+}
+
+#[test]
+fn test_date_time() {
+    let date = Date::from_dos(0x5821).unwrap();
+    assert_eq!(date, Date::new(2024, 1, 1).unwrap());
+    assert_eq!(date.to_dos(), 0x5821);
+    assert_eq!(Date::from_dos(19023).unwrap().to_string(), "2017-02-15");
+    assert_eq!(Date::from_dos(0), None);
+    assert!(Date::new(2023, 2, 29).is_err());
+    assert!(Date::new(2000, 2, 29).is_ok());
+    assert!(Date::new(2108, 1, 1).is_err());
+
+    let time = Time::from_dos(0x645c, 178).unwrap();
+    assert_eq!(time, Time::new(12, 34, 57, 780).unwrap());
+    assert_eq!(time.to_dos(), (0x645c, 178));
+    assert_eq!(time.to_string(), "12:34:57.780");
+    assert_eq!(Time::from_dos(0x645c, 0).unwrap().to_string(), "12:34:56");
+    assert_eq!(Time::from_dos(0xc000, 0), None);
+    assert_eq!(Time::from_dos(0, 200), None);
+
+    let date_time = DateTime::new(date, time);
+    assert_eq!(date_time.to_string(), "2024-01-01 12:34:57.780");
+    assert_eq!(date_time.to_unix(), 1704112497);
+    assert_eq!(DateTime::from_unix(1704112497).time, Time::new(12, 34, 57, 0).unwrap());
+    assert_eq!(DateTime::from_unix(0).to_string(), "1980-01-01 00:00:00");
+    assert_eq!(DateTime::from_unix(1 << 40).to_string(), "2107-12-31 23:59:59");
+    assert_eq!(DateTime::from_unix(951782400).date, Date::new(2000, 2, 29).unwrap());
+
+    assert_eq!("2024-01-01 12:34:57.780".parse::<DateTime>().unwrap(), date_time);
+    assert_eq!("2024-01-01T12:34:57".parse::<DateTime>().unwrap().to_unix(), 1704112497);
+    assert_eq!("@1704112497".parse::<DateTime>().unwrap().to_unix(), 1704112497);
+    assert_eq!("2024-01-01".parse::<DateTime>().unwrap().to_string(), "2024-01-01 00:00:00");
+    assert!("2024-13-01".parse::<DateTime>().is_err());
+    assert_eq!("2024-01-01 12:34:56.5".parse::<DateTime>().unwrap().time.millisecond, 500);
+    assert_eq!("2024-01-01 12:34:56.05".parse::<DateTime>().unwrap().time.millisecond, 50);
+    assert!("2024-01-01 12:34:56.5000".parse::<DateTime>().is_err());
+    assert!("2024-01-01 12:34:56.".parse::<DateTime>().is_err());
+    assert!("2024-01-01.5".parse::<DateTime>().is_err());
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// Number of days in `month` of `year`, or 0 for a month that doesn't exist.
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+impl Date {
+    pub fn new(year: u16, month: u8, day: u8) -> Result<Date, Box<error::Error>> {
+        if year < MIN_YEAR || year > MAX_YEAR
+            || day == 0 || day > days_in_month(year, month)
+        {
+            return Err(From::from(format!(
+                "{:04}-{:02}-{:02} is not a date FAT can store", year, month, day)));
+        }
+        Ok(Date { year: year, month: month, day: day })
+    }
+
+    /// Decode a packed date. Returns `None` for dates that don't exist,
+    /// such as the zero left in entries that never had a date set.
+    pub fn from_dos(date: u16) -> Option<Date> {
+        let year = MIN_YEAR + (date >> 9);
+        let month = (date >> 5 & 0x0f) as u8;
+        let day = (date & 0x1f) as u8;
+        Date::new(year, month, day).ok()
+    }
+
+    /// Pack the date into its directory entry format.
+    pub fn to_dos(&self) -> u16 {
+        (self.year - MIN_YEAR) << 9 | (self.month as u16) << 5 | self.day as u16
+    }
+}
+
+impl Time {
+    pub fn new(hour: u8, minute: u8, second: u8, millisecond: u16)
+        -> Result<Time, Box<error::Error>>
+    {
+        if hour > 23 || minute > 59 || second > 59 || millisecond > 999 {
+            return Err(From::from(format!(
+                "{:02}:{:02}:{:02}.{:03} is not a time of day",
+                hour, minute, second, millisecond)));
+        }
+        Ok(Time { hour: hour, minute: minute, second: second, millisecond: millisecond })
+    }
+
+    /// Decode a packed time along with the count of 10 ms steps stored
+    /// next to creation times, which is 0 for other times.
+    pub fn from_dos(time: u16, centiseconds: u8) -> Option<Time> {
+        if centiseconds > 199 {
+            return None;
+        }
+        let hour = (time >> 11) as u8;
+        let minute = (time >> 5 & 0x3f) as u8;
+        let second = (time & 0x1f) as u8 * 2 + centiseconds / 100;
+        let millisecond = (centiseconds % 100) as u16 * 10;
+        Time::new(hour, minute, second, millisecond).ok()
+    }
+
+    /// Pack the time into its directory entry format, along with the 10 ms
+    /// steps for the creation time field.
+    pub fn to_dos(&self) -> (u16, u8) {
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5
+            | (self.second / 2) as u16;
+        let centiseconds = (self.second % 2) * 100 + (self.millisecond / 10) as u8;
+        (time, centiseconds)
+    }
+}
+
+impl DateTime {
+    pub fn new(date: Date, time: Time) -> DateTime {
+        DateTime { date: date, time: time }
+    }
+
+    /// The time `seconds` after the Unix epoch, clamped to the times FAT
+    /// can store.
+    pub fn from_unix(seconds: i64) -> DateTime {
+        let min = DateTime::new(Date { year: MIN_YEAR, month: 1, day: 1 },
+                                Time { hour: 0, minute: 0, second: 0, millisecond: 0 });
+        let max = DateTime::new(Date { year: MAX_YEAR, month: 12, day: 31 },
+                                Time { hour: 23, minute: 59, second: 59, millisecond: 0 });
+        if seconds <= min.to_unix() {
+            return min;
+        } else if seconds >= max.to_unix() {
+            return max;
+        }
+
+        // Howard Hinnant's civil_from_days, on days since 0000-03-01.
+        let days = seconds.div_euclid(SECONDS_PER_DAY) + 719468;
+        let of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+            - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4
+            - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime::new(
+            Date { year: year as u16, month: month as u8, day: day as u8 },
+            Time {
+                hour: (of_day / 3600) as u8,
+                minute: (of_day / 60 % 60) as u8,
+                second: (of_day % 60) as u8,
+                millisecond: 0,
+            })
+    }
+
+    /// Seconds since the Unix epoch, dropping milliseconds.
+    pub fn to_unix(&self) -> i64 {
+        // Howard Hinnant's days_from_civil.
+        let month = self.date.month as i64;
+        let year = self.date.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * shifted_month + 2) / 5 + self.date.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100
+            + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        days * SECONDS_PER_DAY + self.time.hour as i64 * 3600
+            + self.time.minute as i64 * 60 + self.time.second as i64
+    }
+
+    /// The current time, in UTC.
+    pub fn now() -> DateTime {
+        DateTime::from_system_time(SystemTime::now())
+    }
+
+    /// Convert a host time, clamping it to the times FAT can store.
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let (seconds, millisecond) = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_millis() as u16),
+            Err(_) => (0, 0),
+        };
+        let mut date_time = DateTime::from_unix(seconds);
+        if seconds == date_time.to_unix() {
+            date_time.time.millisecond = millisecond;
+        }
+        date_time
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.to_unix() as u64,
+                                   self.time.millisecond as u32 * 1_000_000)
+    }
+}
+
+impl FromStr for DateTime {
+    type Err = Box<error::Error>;
+
+    /// Parse `YYYY-MM-DD`, `YYYY-MM-DD HH:MM:SS[.mmm]` with a space or `T`
+    /// between date and time, or `@seconds` since the Unix epoch.
+    fn from_str(s: &str) -> Result<DateTime, Box<error::Error>> {
+        let bad_time = || From::from(format!("bad date and time: \"{}\"", s));
+        if s.starts_with('@') {
+            return s[1..].parse::<i64>()
+                .map(DateTime::from_unix)
+                .map_err(|_| bad_time());
+        }
+
+        // Fractions of a second are decimal digits, so `.5` is 500 ms.
+        let (date_time, millisecond) = match s.find('.') {
+            Some(dot) => {
+                let digits = &s[dot + 1 ..];
+                if digits.is_empty() || digits.len() > 3
+                    || !digits.chars().all(|c| c.is_ascii_digit())
+                {
+                    return Err(bad_time());
+                }
+                let millisecond = format!("{:0<3}", digits).parse::<u16>()
+                    .map_err(|_| bad_time())?;
+                (&s[.. dot], millisecond)
+            },
+            None => (s, 0),
+        };
+
+        let mut fields = Vec::new();
+        for field in date_time.split(|c| c == '-' || c == ' ' || c == 'T' || c == ':') {
+            fields.push(field.parse::<u16>().map_err(|_| bad_time())?);
+        }
+        match fields.len() {
+            3 if s.len() == date_time.len() => fields.resize(6, 0),
+            6 => {},
+            _ => return Err(bad_time()),
+        }
+        if fields[1] > 12 || fields[2] > 31 || fields[3] > 23 || fields[4] > 59
+            || fields[5] > 59
+        {
+            return Err(bad_time());
+        }
+        Ok(DateTime::new(
+            Date::new(fields[0], fields[1] as u8, fields[2] as u8)?,
+            Time::new(fields[3] as u8, fields[4] as u8, fields[5] as u8, millisecond)?))
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.hour, self.minute, self.second)?;
+        if self.millisecond != 0 {
+            write!(f, ".{:03}", self.millisecond)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.date, self.time)
+    }
+}
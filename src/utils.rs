@@ -3,7 +3,7 @@ macro_rules! errorf {
         From::from(format!(
             $fmt,
             $( $arg ),*
-        ));
+        ))
     }
 }
 